@@ -0,0 +1,8 @@
+-- Token buckets limiting the rate at which the email delivery worker sends
+-- mail. Persisted so that restarting the worker doesn't reset e.g. the
+-- daily sending budget.
+CREATE TABLE email_send_budgets (
+   name TEXT PRIMARY KEY NOT NULL,
+   tokens DOUBLE PRECISION NOT NULL,
+   refilled_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,9 @@
+-- Transactional email (e.g. subscription confirmations) is delivered ahead of
+-- bulk email (e.g. new post announcements)
+CREATE TYPE email_priority AS ENUM ('transactional', 'bulk');
+
+ALTER TABLE email_delivery_queue
+ADD COLUMN priority email_priority NOT NULL DEFAULT 'bulk';
+
+CREATE INDEX email_delivery_queue_priority_send_after_idx
+ON email_delivery_queue (priority, send_after);
@@ -0,0 +1,41 @@
+-- Bulk emails (e.g. new post announcements) store their content once in
+-- email_campaigns rather than in every queued task
+CREATE TYPE email_campaign_status AS ENUM ('active', 'paused', 'cancelled');
+
+CREATE TABLE email_campaigns (
+   id uuid PRIMARY KEY NOT NULL,
+   name TEXT NOT NULL,
+   subject TEXT NOT NULL,
+   email_html TEXT NOT NULL,
+   email_text TEXT NOT NULL,
+   status email_campaign_status NOT NULL DEFAULT 'active',
+   created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+   n_sent INTEGER NOT NULL DEFAULT 0
+);
+
+ALTER TABLE email_delivery_queue
+ADD COLUMN campaign_id uuid REFERENCES email_campaigns (id),
+ALTER COLUMN subject DROP NOT NULL,
+ALTER COLUMN email_html DROP NOT NULL,
+ALTER COLUMN email_text DROP NOT NULL,
+ADD CONSTRAINT email_delivery_queue_content_check CHECK (
+   campaign_id IS NOT NULL
+   OR (subject IS NOT NULL AND email_html IS NOT NULL AND email_text IS NOT NULL)
+);
+
+CREATE INDEX email_delivery_queue_campaign_id_idx
+ON email_delivery_queue (campaign_id);
+
+-- Tasks which will never be delivered, kept for inspection
+CREATE TABLE email_delivery_dead_letters (
+   id uuid PRIMARY KEY NOT NULL,
+   subscriber_id uuid NOT NULL,
+   campaign_id uuid REFERENCES email_campaigns (id),
+   subject TEXT,
+   email_html TEXT,
+   email_text TEXT,
+   created_at TIMESTAMPTZ NOT NULL,
+   n_retries INTEGER NOT NULL,
+   reason TEXT NOT NULL,
+   dead_lettered_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,4 @@
+-- Delay applied before the latest retry of a task, needed to compute
+-- decorrelated jitter for the next one
+ALTER TABLE email_delivery_queue
+ADD COLUMN backoff_ms BIGINT;
@@ -0,0 +1,4 @@
+-- Preview text shown by mail clients next to the subject line
+ALTER TABLE email_delivery_queue ADD COLUMN preheader TEXT;
+ALTER TABLE email_campaigns ADD COLUMN preheader TEXT NOT NULL DEFAULT '';
+ALTER TABLE email_delivery_dead_letters ADD COLUMN preheader TEXT;
@@ -0,0 +1,27 @@
+-- Message-IDs of delivered email, so bounces can be traced back to subscribers
+CREATE TABLE email_delivery_log (
+   message_id TEXT PRIMARY KEY NOT NULL,
+   subscriber_id uuid NOT NULL,
+   campaign_id uuid REFERENCES email_campaigns (id),
+   sent_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE TYPE email_bounce_kind AS ENUM ('hard_bounce', 'soft_bounce', 'complaint');
+
+CREATE TABLE email_bounces (
+   id uuid PRIMARY KEY NOT NULL,
+   subscriber_id uuid NOT NULL,
+   kind email_bounce_kind NOT NULL,
+   status TEXT,
+   diagnostic TEXT,
+   -- Message-ID of the bounce report, so duplicate reports are ignored
+   report_id TEXT UNIQUE,
+   received_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX email_bounces_subscriber_id_idx ON email_bounces (subscriber_id);
+
+-- Suppressed subscribers are no longer sent email
+ALTER TABLE subscriptions
+ADD COLUMN suppressed_at TIMESTAMPTZ,
+ADD COLUMN suppression_reason TEXT;
@@ -0,0 +1,34 @@
+-- Identifies an address without storing it, so suppressions outlive the
+-- subscriptions they were made for
+CREATE FUNCTION email_address_hash(email TEXT) RETURNS TEXT
+LANGUAGE SQL IMMUTABLE STRICT PARALLEL SAFE
+AS $$ SELECT encode(sha256(convert_to(lower(trim(email)), 'UTF8')), 'hex') $$;
+
+CREATE TYPE email_suppression_reason AS ENUM ('unsubscribed', 'bounced', 'complaint', 'manual');
+
+-- Addresses which must not be sent email. Manual entries can be added with
+-- INSERT INTO email_suppressions (address_hash, reason)
+-- VALUES (email_address_hash('reader@tld.com'), 'manual');
+CREATE TABLE email_suppressions (
+   address_hash TEXT PRIMARY KEY NOT NULL,
+   reason email_suppression_reason NOT NULL,
+   detail TEXT,
+   suppressed_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+INSERT INTO email_suppressions (address_hash, reason, detail, suppressed_at)
+SELECT
+   email_address_hash(email),
+   CASE
+      WHEN suppression_reason LIKE 'complaint%' THEN 'complaint'
+      ELSE 'bounced'
+   END::email_suppression_reason,
+   suppression_reason,
+   suppressed_at
+FROM subscriptions
+WHERE suppressed_at IS NOT NULL
+ON CONFLICT DO NOTHING;
+
+ALTER TABLE subscriptions
+DROP COLUMN suppressed_at,
+DROP COLUMN suppression_reason;
@@ -0,0 +1,31 @@
+CREATE TYPE delivery_mode AS ENUM ('immediate', 'weekly_digest');
+
+ALTER TABLE subscriptions
+ADD COLUMN delivery_mode delivery_mode NOT NULL DEFAULT 'immediate';
+
+-- Posts announced before this migration have no summary or publication time,
+-- so they are never included in a digest
+ALTER TABLE blog_posts
+ADD COLUMN published_at TIMESTAMPTZ,
+ADD COLUMN title TEXT,
+ADD COLUMN url TEXT,
+ADD COLUMN date TEXT,
+ADD COLUMN excerpt TEXT,
+ADD COLUMN reading_time_minutes INTEGER,
+ADD COLUMN hero_image TEXT;
+
+ALTER TABLE blog_posts ALTER COLUMN published_at SET DEFAULT NOW();
+
+CREATE INDEX blog_posts_published_at_idx ON blog_posts (published_at);
+
+-- Each digest covers the posts published during its period, which starts where
+-- the previous one ended. Periods are recorded even when there was nothing to
+-- send, and the unique start stops a period being sent twice.
+CREATE TABLE digest_runs (
+   id uuid PRIMARY KEY NOT NULL,
+   period_start TIMESTAMPTZ NOT NULL UNIQUE,
+   period_end TIMESTAMPTZ NOT NULL,
+   n_posts INTEGER NOT NULL,
+   campaign_id uuid REFERENCES email_campaigns (id),
+   created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,11 @@
+-- Confirmation tokens are single use and expire. Existing tokens get a fresh
+-- lifetime so that links already in people's inboxes keep working for a while.
+ALTER TABLE subscription_tokens
+ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+ADD COLUMN expires_at TIMESTAMPTZ NOT NULL DEFAULT NOW() + INTERVAL '48 hours',
+ADD COLUMN used_at TIMESTAMPTZ;
+
+ALTER TABLE subscription_tokens ALTER COLUMN expires_at DROP DEFAULT;
+
+CREATE INDEX subscription_tokens_subscriber_id_idx ON subscription_tokens (subscriber_id);
+CREATE INDEX subscription_tokens_expires_at_idx ON subscription_tokens (expires_at);
@@ -0,0 +1,10 @@
+-- Accepted sign-ups, used as a sliding window log to rate limit
+-- POST /subscriptions by client IP and by (hashed) email address
+CREATE TABLE signup_attempts (
+   id BIGSERIAL PRIMARY KEY,
+   key TEXT NOT NULL,
+   attempted_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX signup_attempts_key_idx ON signup_attempts (key, attempted_at);
+CREATE INDEX signup_attempts_attempted_at_idx ON signup_attempts (attempted_at);
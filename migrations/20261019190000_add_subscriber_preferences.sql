@@ -0,0 +1,18 @@
+-- Subscribers can pause delivery until a given time
+ALTER TABLE subscriptions ADD COLUMN paused_until TIMESTAMPTZ;
+
+-- Address changes take effect once the new address is confirmed
+CREATE TABLE email_change_requests (
+    token TEXT PRIMARY KEY,
+    subscriber_id UUID NOT NULL REFERENCES subscriptions (id) ON DELETE CASCADE,
+    new_email TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    expires_at TIMESTAMPTZ NOT NULL,
+    used_at TIMESTAMPTZ
+);
+
+CREATE INDEX email_change_requests_subscriber_id_idx ON email_change_requests (subscriber_id);
+
+-- Mail can be addressed somewhere other than the subscriber's current address,
+-- e.g. to confirm a new one
+ALTER TABLE email_delivery_queue ADD COLUMN recipient TEXT;
@@ -0,0 +1,18 @@
+-- Topics subscribers can choose between. A subscriber who hasn't chosen any
+-- hears about everything.
+CREATE TABLE topics (
+    slug TEXT PRIMARY KEY,
+    name TEXT NOT NULL
+);
+
+INSERT INTO topics (slug, name) VALUES
+    ('software-engineering', 'Software engineering'),
+    ('distributed-systems', 'Distributed systems'),
+    ('rust', 'Rust'),
+    ('career', 'Career');
+
+CREATE TABLE subscription_topics (
+    subscriber_id UUID NOT NULL REFERENCES subscriptions (id) ON DELETE CASCADE,
+    topic TEXT NOT NULL REFERENCES topics (slug) ON DELETE CASCADE,
+    PRIMARY KEY (subscriber_id, topic)
+);
@@ -0,0 +1,5 @@
+-- Posts are tagged with the topics they're about. Untagged posts, including
+-- every post published before this migration, go to all subscribers.
+ALTER TABLE blog_posts ADD COLUMN tags TEXT[] NOT NULL DEFAULT '{}';
+
+CREATE INDEX subscription_topics_topic_idx ON subscription_topics (topic);
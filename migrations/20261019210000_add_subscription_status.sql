@@ -0,0 +1,27 @@
+-- Subscribers are no longer deleted when they leave, since queued and logged
+-- email still refers to them. Their status says whether to send them anything.
+CREATE TYPE subscription_status AS ENUM ('pending', 'active', 'unsubscribed', 'bounced');
+
+ALTER TABLE subscriptions
+ADD COLUMN status subscription_status NOT NULL DEFAULT 'pending',
+ADD COLUMN unsubscribed_at TIMESTAMPTZ;
+
+UPDATE subscriptions SET status = 'active' WHERE confirmed;
+
+-- Subscribers whose address bounced, or who marked our email as spam, were
+-- kept but suppressed
+UPDATE subscriptions
+SET status = 'bounced'
+FROM email_suppressions
+WHERE email_suppressions.address_hash = email_address_hash(subscriptions.email)
+AND email_suppressions.reason = 'bounced';
+
+UPDATE subscriptions
+SET status = 'unsubscribed', unsubscribed_at = email_suppressions.suppressed_at
+FROM email_suppressions
+WHERE email_suppressions.address_hash = email_address_hash(subscriptions.email)
+AND email_suppressions.reason = 'complaint';
+
+ALTER TABLE subscriptions DROP COLUMN confirmed;
+
+CREATE INDEX subscriptions_status_idx ON subscriptions (status);
@@ -0,0 +1,37 @@
+-- Append-only record of how and when each subscriber consented, changed their
+-- mind or was removed, so that consent can be proven later
+CREATE TYPE subscription_event_kind AS ENUM (
+    'signed_up',
+    'confirmed',
+    'confirmation_resent',
+    'preferences_changed',
+    'email_changed',
+    'unsubscribed',
+    'bounced'
+);
+
+CREATE TABLE subscription_events (
+    id BIGSERIAL PRIMARY KEY,
+    subscriber_id UUID NOT NULL REFERENCES subscriptions (id),
+    kind subscription_event_kind NOT NULL,
+    occurred_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    -- Where the request came from, for events a person triggered
+    ip_address TEXT,
+    user_agent TEXT,
+    -- What triggered the event, e.g. the form used to sign up
+    source TEXT,
+    detail TEXT
+);
+
+CREATE INDEX subscription_events_subscriber_id_idx
+ON subscription_events (subscriber_id, occurred_at);
+
+CREATE FUNCTION forbid_subscription_event_changes() RETURNS trigger AS $$
+BEGIN
+    RAISE EXCEPTION 'subscription_events is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER subscription_events_append_only
+BEFORE UPDATE OR DELETE ON subscription_events
+FOR EACH ROW EXECUTE FUNCTION forbid_subscription_event_changes();
@@ -1,7 +1,13 @@
-use shared::util::read_env_or_panic;
+use shared::{
+    blog_post::{self, BlogPostSummary},
+    digest::DeliveryMode,
+    email_campaigns, email_delivery_queue,
+    email_templates::EmailTemplates,
+    util::{app_url, read_env_or_panic},
+};
 use sqlx::{
     postgres::{PgConnectOptions, PgConnection, PgSslMode},
-    Connection, Executor,
+    Connection,
 };
 use std::process::{exit, Command};
 
@@ -44,9 +50,10 @@ async fn main() -> Result<(), anyhow::Error> {
         )
         .ssl_mode(PgSslMode::Prefer);
 
+    let email_templates = EmailTemplates::load()?;
+
     let mut conn = PgConnection::connect_with(&options).await?;
     let mut transaction = conn.begin().await?;
-    let app_base_url = read_env_or_panic("APP_BASE_URL");
 
     for file in new_files {
         let slug = file
@@ -56,41 +63,55 @@ async fn main() -> Result<(), anyhow::Error> {
             .unwrap_or_else(|| {
                 panic!("Problem {}: Blog entries should have .html extension", file)
             });
-        let link = format!("{}/blog/{}", app_base_url, slug);
+        let link = app_url(&format!("/blog/{}", slug));
 
-        let subject = "New blog post";
-        let email_html = format!(
-            "<p>New blog post! Click <a href={}>here</a> to view.p>",
-            link
-        );
-        let email_text = format!("New blog post! Available at {}.", link);
-        let query = sqlx::query!(
-            r#"
-            INSERT INTO email_delivery_queue
-            SELECT gen_random_uuid(), id, $1, $2, $3
-            FROM subscriptions
-            WHERE confirmed = true
-            AND $4 NOT IN (SELECT slug FROM blog_posts)
-            "#,
-            subject,
-            email_html,
-            email_text,
-            slug
-        );
-        transaction.execute(query).await.unwrap_or_else(|_| {
+        // Read the post from git, as the dispatcher image only contains .git/
+        let output = Command::new("git")
+            .args(["show", &format!("HEAD:{}", file)])
+            .output()?;
+        if !output.status.success() {
+            panic!("Failed to read {} from git", file);
+        }
+        let post = BlogPostSummary::parse(&String::from_utf8(output.stdout)?, &link)
+            .unwrap_or_else(|e| panic!("Failed to parse blog post {}: {}", file, e));
+
+        // Posts are only announced once
+        let registered = blog_post::register_post(&mut *transaction, slug, &post)
+            .await
+            .unwrap_or_else(|_| panic!("Failed to register new post {} in blog_posts table", slug));
+        if !registered {
+            eprintln!("Post {} has already been announced.", slug);
+            continue;
+        }
+
+        let content = email_templates.new_post(&post)?;
+        let campaign_id = email_campaigns::create_campaign(&mut *transaction, slug, &content)
+            .await
+            .unwrap_or_else(|_| panic!("Failed to create email campaign for new post {}", &file));
+
+        // Digest subscribers hear about the post in their next weekly digest
+        let n_queued = email_delivery_queue::push_campaign_tasks(
+            &mut *transaction,
+            campaign_id,
+            DeliveryMode::Immediate,
+            &post.tags,
+        )
+        .await
+        .unwrap_or_else(|_| {
             panic!(
                 "Failed to enqueue email notifications for new post {}",
                 &file
             )
         });
-
-        let query = sqlx::query!(r#" INSERT INTO blog_posts (slug) VALUES ($1)"#, slug);
-        transaction
-            .execute(query)
-            .await
-            .unwrap_or_else(|_| panic!("Failed to register new post {} in blog_posts table", slug));
+        eprintln!(
+            "Queued {} emails for campaign {} ({})",
+            n_queued, campaign_id, slug
+        );
     }
 
+    // Wake the email delivery worker once the new tasks are committed
+    email_delivery_queue::notify_workers(&mut *transaction).await?;
+
     transaction.commit().await?;
 
     Ok(())
@@ -1,16 +1,23 @@
 use actix_session::storage::CookieSessionStore;
 use actix_session::SessionMiddleware;
 use actix_web::{cookie::Key, web, App, HttpServer};
-use core::time::Duration;
 use dotenvy::dotenv;
-use lettre::transport::smtp::authentication::Credentials;
-use lettre::transport::smtp::AsyncSmtpTransport;
-use lettre::transport::smtp::PoolConfig;
-use lettre::Tokio1Executor;
 use secrecy::{ExposeSecret, Secret};
 use shared::{
-    email_delivery_worker::worker, email_delivery_worker::EmailClient, routes, ssr::SsrCommon,
-    util::read_env_or_panic,
+    bot_protection::BotProtection,
+    digest::digest_scheduler,
+    email_delivery_worker::worker,
+    email_delivery_worker::{
+        DkimSettings, EmailBackend, EmailClient, EmailTransport, RateLimiter, RateLimits,
+        RetryPolicies,
+    },
+    email_templates::EmailTemplates,
+    routes::{self, webhooks::BounceWebhookConfig},
+    signed_token::TokenSigner,
+    signup_rate_limit::{SignupRateLimiter, SignupRateLimits},
+    ssr::SsrCommon,
+    subscription_tokens::token_cleanup,
+    util::{read_env_or_panic, read_optional_env},
 };
 use sqlx::{
     postgres::{PgConnectOptions, PgSslMode},
@@ -27,6 +34,8 @@ async fn main() -> std::io::Result<()> {
 
     log::info!("Setting up SSR...");
     let ssr_common = web::Data::new(SsrCommon::load().expect("Failed to set up SSR"));
+    let email_templates =
+        web::Data::new(EmailTemplates::load().expect("Failed to load email templates"));
 
     log::info!("Establishing database connection...");
 
@@ -49,30 +58,80 @@ async fn main() -> std::io::Result<()> {
 
     log::info!("Setting up email client...");
     let email_address = read_env_or_panic("BLOG_EMAIL_ADDRESS");
-    let email_password = Secret::new(read_env_or_panic("BLOG_EMAIL_PASSWORD"));
-    let email_creds =
-        Credentials::new(email_address.clone(), email_password.expose_secret().into());
-
-    let email_transport = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay("smtp.gmail.com")
-        .expect("Failed to create smtp client")
-        .credentials(email_creds)
-        .timeout(Some(Duration::from_secs(10)))
-        .pool_config(PoolConfig::new().max_size(20))
-        .port(587)
-        .build();
-
-    let email_client = EmailClient::new(Arc::new(email_transport), &email_address)
+    let email_transport = EmailBackend::from_env()
+        .build()
+        .expect("Failed to create email transport");
+    let mail_catcher = email_transport.mail_catcher().cloned().map(web::Data::new);
+    if mail_catcher.is_some() {
+        log::warn!("Email is being captured rather than sent. Read it at /dev/mail");
+    }
+
+    let mut email_client = EmailClient::new(Arc::new(email_transport), &email_address)
         .expect("Failed to setup email client");
+    let bounce_address = read_optional_env::<lettre::Address>("EMAIL_BOUNCE_ADDRESS");
+    if let Some(bounce_address) = &bounce_address {
+        email_client = email_client.with_bounce_address(bounce_address.clone());
+    }
+    if let Some(dkim) = DkimSettings::from_env() {
+        log::info!(
+            "DKIM signing as {}._domainkey.{}",
+            dkim.selector,
+            dkim.domain
+        );
+        email_client = email_client.with_dkim(dkim.load().expect("Failed to load DKIM key"));
+    }
 
-    log::info!("Setting up email delivery background worker...");
-    let worker_task = tokio::spawn(worker(Arc::new(email_client), Arc::new(pgpool)));
+    let rate_limits = RateLimits::default()
+        .per_second(read_optional_env("EMAIL_RATE_LIMIT_PER_SECOND"))
+        .per_hour(read_optional_env("EMAIL_RATE_LIMIT_PER_HOUR"))
+        .per_day(read_optional_env("EMAIL_RATE_LIMIT_PER_DAY"));
 
-    // Set up secret key for flash messaging middleware
+    // Set up secret key for flash messaging middleware and signed links
     let hmac_secret = Secret::new(read_env_or_panic("APP_HMAC_SECRET"));
     let secret_key = Key::from(hmac_secret.expose_secret().as_bytes());
+    let token_signer = web::Data::new(TokenSigner::new(&hmac_secret));
+
+    log::info!("Setting up weekly digest scheduler...");
+    let digest_task = tokio::spawn(digest_scheduler(
+        Arc::new(pgpool.clone()),
+        email_templates.clone().into_inner(),
+    ));
+
+    let token_cleanup_task = tokio::spawn(token_cleanup(Arc::new(pgpool.clone())));
+
+    log::info!("Setting up email delivery background worker...");
+    let worker_task = tokio::spawn(worker(
+        Arc::new(email_client),
+        email_templates.clone().into_inner(),
+        token_signer.clone().into_inner(),
+        Arc::new(pgpool),
+        Arc::new(RateLimiter::new(rate_limits)),
+        Arc::new(RetryPolicies::default()),
+    ));
+
+    let bot_protection = web::Data::new(
+        BotProtection::new(&hmac_secret)
+            .min_fill_seconds(read_optional_env("SIGNUP_MIN_FILL_SECONDS"))
+            .proof_of_work(read_optional_env("SIGNUP_POW_DIFFICULTY")),
+    );
+
+    let signup_rate_limiter = web::Data::new(SignupRateLimiter::new(
+        SignupRateLimits::default()
+            .per_ip_per_hour(read_optional_env("SIGNUP_LIMIT_PER_IP_PER_HOUR"))
+            .per_email_per_day(read_optional_env("SIGNUP_LIMIT_PER_EMAIL_PER_DAY")),
+    ));
+
+    let bounce_webhook = read_optional_env::<String>("BOUNCE_WEBHOOK_TOKEN").map(|token| {
+        web::Data::new(BounceWebhookConfig {
+            token: Secret::new(token),
+            bounce_address,
+        })
+    });
 
     log::info!("Setting up server...");
     let server = HttpServer::new(move || {
+        let mail_catcher = mail_catcher.clone();
+        let bounce_webhook = bounce_webhook.clone();
         App::new()
             .wrap(TracingLogger::default())
             .wrap(
@@ -80,26 +139,85 @@ async fn main() -> std::io::Result<()> {
                     .build(),
             )
             .app_data(ssr_common.clone())
+            .app_data(email_templates.clone())
             .app_data(connection_pool.clone())
+            .app_data(token_signer.clone())
+            .app_data(signup_rate_limiter.clone())
+            .app_data(bot_protection.clone())
             .route(
                 "/health_check",
                 web::get().to(routes::health_check::health_check),
             )
             .route("/blog", web::get().to(routes::blog::get))
+            .route("/newsletter", web::get().to(routes::newsletter::archive))
+            .route("/newsletter/{id}", web::get().to(routes::newsletter::issue))
             .route(
                 "/subscriptions",
-                web::post()
-                    .to(routes::subscriptions::subscribe::<AsyncSmtpTransport<Tokio1Executor>>),
+                web::post().to(routes::subscriptions::subscribe::<EmailTransport>),
+            )
+            .route(
+                "/subscriptions/confirm",
+                web::get().to(routes::subscriptions::confirm_form),
             )
             .route(
                 "/subscriptions/confirm",
-                web::get().to(routes::subscriptions::confirm::<AsyncSmtpTransport<Tokio1Executor>>),
+                web::post().to(routes::subscriptions::confirm::<EmailTransport>),
+            )
+            .route(
+                "/subscriptions/confirm/resend",
+                web::post().to(routes::subscriptions::resend_confirmation),
+            )
+            .route(
+                "/subscriptions/preferences",
+                web::get().to(routes::subscriptions::preferences_page),
+            )
+            .route(
+                "/subscriptions/preferences",
+                web::post().to(routes::subscriptions::update_preferences),
+            )
+            .route(
+                "/subscriptions/preferences/email",
+                web::post().to(routes::subscriptions::change_email),
+            )
+            .route(
+                "/subscriptions/preferences/email/confirm",
+                web::get().to(routes::subscriptions::confirm_email_change_form),
+            )
+            .route(
+                "/subscriptions/preferences/email/confirm",
+                web::post().to(routes::subscriptions::confirm_email_change),
             )
             .route(
                 "/subscriptions/unsubscribe",
-                web::get()
-                    .to(routes::subscriptions::unsubscribe::<AsyncSmtpTransport<Tokio1Executor>>),
+                web::get().to(routes::subscriptions::unsubscribe_form),
+            )
+            .route(
+                "/subscriptions/unsubscribe",
+                web::post().to(routes::subscriptions::unsubscribe),
+            )
+            .route(
+                "/subscriptions/unsubscribe/one-click",
+                web::post().to(routes::subscriptions::unsubscribe_one_click),
             )
+            .configure(move |cfg| {
+                if let Some(bounce_webhook) = bounce_webhook {
+                    cfg.app_data(bounce_webhook).route(
+                        "/webhooks/bounces",
+                        web::post().to(routes::webhooks::bounces),
+                    );
+                }
+            })
+            .configure(move |cfg| {
+                if let Some(mail_catcher) = mail_catcher {
+                    cfg.app_data(mail_catcher)
+                        .route("/dev/mail", web::get().to(routes::dev_mail::inbox))
+                        .route("/dev/mail/{id}", web::get().to(routes::dev_mail::message))
+                        .route(
+                            "/dev/mail/{id}/html",
+                            web::get().to(routes::dev_mail::message_html),
+                        );
+                }
+            })
     })
     .bind(("0.0.0.0", 8001))?
     .run();
@@ -108,6 +226,8 @@ async fn main() -> std::io::Result<()> {
     tokio::select! {
         _ = server => {},
         _ = worker_task => {},
+        _ = digest_task => {},
+        _ = token_cleanup_task => {},
     };
 
     Ok(())
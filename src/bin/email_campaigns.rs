@@ -0,0 +1,113 @@
+use chrono::SecondsFormat;
+use shared::{
+    email_campaigns, email_delivery_queue, subscription_events::events_for_subscriber,
+    util::read_env_or_panic,
+};
+use sqlx::{
+    postgres::{PgConnectOptions, PgConnection, PgSslMode},
+    Connection,
+};
+use std::process::exit;
+use uuid::Uuid;
+
+const USAGE: &str = "Usage: email-campaigns \
+    <list | pause ID | resume ID | cancel ID | subscriber-events SUBSCRIBER_ID>";
+
+#[tokio::main]
+async fn main() -> Result<(), anyhow::Error> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let (command, campaign_id) = match args.as_slice() {
+        [command] => (command.as_str(), None),
+        [command, id] => match id.parse::<Uuid>() {
+            Ok(id) => (command.as_str(), Some(id)),
+            Err(_) => {
+                eprintln!("Invalid id {}", id);
+                exit(1);
+            }
+        },
+        _ => {
+            eprintln!("{}", USAGE);
+            exit(1);
+        }
+    };
+
+    let options = PgConnectOptions::new()
+        .host(&read_env_or_panic("DB_HOST"))
+        .username(&read_env_or_panic("DB_USER"))
+        .password(&read_env_or_panic("DB_PASSWORD"))
+        .database(&read_env_or_panic("DB_NAME"))
+        .port(
+            read_env_or_panic("DB_PORT")
+                .parse::<u16>()
+                .expect("DB_PORT was not a u16"),
+        )
+        .ssl_mode(PgSslMode::Prefer);
+
+    let mut conn = PgConnection::connect_with(&options).await?;
+
+    let found = match (command, campaign_id) {
+        ("list", None) => {
+            println!(
+                "{:<36}  {:<9}  {:>6}  {:>6}  {:>6}  {:>6}  NAME",
+                "ID", "STATUS", "QUEUED", "SENT", "FAILED", "DEAD"
+            );
+            for c in email_campaigns::campaign_progress(&mut conn).await? {
+                println!(
+                    "{:<36}  {:<9}  {:>6}  {:>6}  {:>6}  {:>6}  {}",
+                    c.id, c.status, c.n_queued, c.n_sent, c.n_failed, c.n_dead_lettered, c.name
+                );
+            }
+            true
+        }
+        ("pause", Some(id)) => email_campaigns::set_campaign_paused(&mut conn, id, true).await?,
+        ("resume", Some(id)) => {
+            let mut transaction = conn.begin().await?;
+            let found = email_campaigns::set_campaign_paused(&mut *transaction, id, false).await?;
+            email_delivery_queue::notify_workers(&mut *transaction).await?;
+            transaction.commit().await?;
+            found
+        }
+        ("cancel", Some(id)) => {
+            let mut transaction = conn.begin().await?;
+            let discarded = email_campaigns::cancel_campaign(&mut transaction, id).await?;
+            transaction.commit().await?;
+            if let Some(n) = discarded {
+                println!("Discarded {} queued emails", n);
+            }
+            discarded.is_some()
+        }
+        ("subscriber-events", Some(id)) => {
+            let events = events_for_subscriber(&mut conn, id).await?;
+            if events.is_empty() {
+                eprintln!("No events recorded for that subscriber");
+                exit(1);
+            }
+            println!(
+                "{:<25}  {:<19}  {:<22}  {:<15}  DETAIL",
+                "OCCURRED AT", "EVENT", "SOURCE", "IP ADDRESS"
+            );
+            for e in events {
+                println!(
+                    "{:<25}  {:<19}  {:<22}  {:<15}  {}",
+                    e.occurred_at.to_rfc3339_opts(SecondsFormat::Secs, true),
+                    e.kind,
+                    e.source.as_deref().unwrap_or("-"),
+                    e.ip_address.as_deref().unwrap_or("-"),
+                    e.detail.as_deref().unwrap_or("")
+                );
+            }
+            true
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            exit(1);
+        }
+    };
+
+    if !found {
+        eprintln!("No campaign with that id, or it has been cancelled");
+        exit(1);
+    }
+
+    Ok(())
+}
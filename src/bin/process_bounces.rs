@@ -0,0 +1,142 @@
+use secrecy::Secret;
+use shared::{
+    bounces::{
+        parse_report, parse_unsubscribe_request, process_bounce, process_unsubscribe_request,
+        BounceOutcome, UnsubscribeOutcome,
+    },
+    signed_token::TokenSigner,
+    util::{read_env_or_panic, read_optional_env},
+};
+use sqlx::{
+    postgres::{PgConnectOptions, PgSslMode},
+    PgPool,
+};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+const USAGE: &str = "Usage: process-bounces <MAILDIR | MBOX>";
+
+#[tokio::main]
+async fn main() -> Result<(), anyhow::Error> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let [path] = args.as_slice() else {
+        eprintln!("{}", USAGE);
+        exit(1);
+    };
+    let path = PathBuf::from(path);
+
+    let options = PgConnectOptions::new()
+        .host(&read_env_or_panic("DB_HOST"))
+        .username(&read_env_or_panic("DB_USER"))
+        .password(&read_env_or_panic("DB_PASSWORD"))
+        .database(&read_env_or_panic("DB_NAME"))
+        .port(
+            read_env_or_panic("DB_PORT")
+                .parse::<u16>()
+                .expect("DB_PORT was not a u16"),
+        )
+        .ssl_mode(PgSslMode::Prefer);
+    let pool = PgPool::connect_with(options).await?;
+    let bounce_address = read_optional_env::<lettre::Address>("EMAIL_BOUNCE_ADDRESS");
+    let token_signer = TokenSigner::new(&Secret::new(read_env_or_panic("APP_HMAC_SECRET")));
+
+    let messages = if path.is_dir() {
+        read_maildir(&path)?
+    } else {
+        read_mbox(&path)?
+    };
+
+    let mut n_suppressed = 0;
+    let mut n_unsubscribed = 0;
+    for (source, raw) in messages {
+        if let Some(request) = parse_unsubscribe_request(&raw) {
+            match process_unsubscribe_request(&pool, &token_signer, &request).await? {
+                UnsubscribeOutcome::Unsubscribed { subscriber_id } => {
+                    n_unsubscribed += 1;
+                    eprintln!("Unsubscribed subscriber {} by email", subscriber_id);
+                }
+                UnsubscribeOutcome::InvalidToken => {
+                    eprintln!(
+                        "{}: unsubscribe request with an invalid token, skipping",
+                        source.display()
+                    );
+                }
+            }
+            mark_processed(&source)?;
+            continue;
+        }
+        let Some(report) = parse_report(&raw) else {
+            eprintln!("{}: not a bounce report, skipping", source.display());
+            mark_processed(&source)?;
+            continue;
+        };
+        match process_bounce(&pool, &report, bounce_address.as_ref()).await? {
+            BounceOutcome::Suppressed { subscriber_id } => {
+                n_suppressed += 1;
+                eprintln!("Suppressed subscriber {} ({})", subscriber_id, report.kind);
+            }
+            BounceOutcome::Recorded { subscriber_id } => {
+                eprintln!("Recorded {} for subscriber {}", report.kind, subscriber_id);
+            }
+            BounceOutcome::Duplicate => {}
+            BounceOutcome::UnknownRecipient => {
+                eprintln!(
+                    "{}: could not trace {} to a subscriber, skipping",
+                    source.display(),
+                    report.kind
+                );
+            }
+        }
+        mark_processed(&source)?;
+    }
+
+    eprintln!("Suppressed {} subscribers", n_suppressed);
+    eprintln!("Unsubscribed {} subscribers by email", n_unsubscribed);
+    Ok(())
+}
+
+/// Unprocessed messages in a maildir, i.e. those in `new/`.
+fn read_maildir(dir: &Path) -> Result<Vec<(PathBuf, Vec<u8>)>, std::io::Error> {
+    let mut messages = vec![];
+    for entry in std::fs::read_dir(dir.join("new"))? {
+        let path = entry?.path();
+        if path.is_file() {
+            let raw = std::fs::read(&path)?;
+            messages.push((path, raw));
+        }
+    }
+    Ok(messages)
+}
+
+/// Every message in an mbox file. Reports already processed are recognised by
+/// their Message-ID, so the file can be processed repeatedly.
+fn read_mbox(file: &Path) -> Result<Vec<(PathBuf, Vec<u8>)>, std::io::Error> {
+    let contents = std::fs::read_to_string(file)?;
+    let mut messages: Vec<(PathBuf, Vec<u8>)> = vec![];
+    for line in contents.split_inclusive('\n') {
+        if line.starts_with("From ") {
+            messages.push((file.to_path_buf(), vec![]));
+        } else if let Some((_, raw)) = messages.last_mut() {
+            // Undo mboxrd quoting of lines beginning with "From "
+            let line = match line.strip_prefix('>') {
+                Some(unquoted) if unquoted.trim_start_matches('>').starts_with("From ") => unquoted,
+                _ => line,
+            };
+            raw.extend_from_slice(line.as_bytes());
+        }
+    }
+    Ok(messages)
+}
+
+/// Move a maildir message from `new/` to `cur/`, flagged as seen.
+fn mark_processed(path: &Path) -> Result<(), std::io::Error> {
+    let Some(maildir) = path
+        .parent()
+        .filter(|dir| dir.ends_with("new"))
+        .and_then(Path::parent)
+    else {
+        return Ok(());
+    };
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    std::fs::rename(path, maildir.join("cur").join(format!("{}:2,S", name)))
+}
@@ -0,0 +1,262 @@
+use chrono::{DateTime, Utc};
+use scraper::{Html, Selector};
+use serde::Serialize;
+use sqlx::{Executor, Postgres};
+use url::Url;
+
+/// Average adult silent reading speed, used to estimate reading time.
+const WORDS_PER_MINUTE: usize = 200;
+
+/// Excerpts longer than this are cut at a word boundary.
+const MAX_EXCERPT_CHARS: usize = 300;
+
+/// Details of a post in `blog/`, read from its HTML for announcement emails.
+/// Posts start with an `<h2>` title and a `<div class="date">`, followed by the
+/// body in `<div class="blog-post-content">`. The topics a post is about can be
+/// listed in `<meta name="tags" content="rust, career">`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BlogPostSummary {
+    pub title: String,
+    pub date: Option<String>,
+    /// The start of the post's first paragraph
+    pub excerpt: String,
+    pub reading_time_minutes: usize,
+    /// Absolute URL of the first image in the post
+    pub hero_image: Option<String>,
+    pub url: String,
+    /// Slugs of the topics the post is about. Untagged posts are for everyone.
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum BlogPostParseError {
+    #[error("Blog post has no <h2> title")]
+    MissingTitle,
+    #[error("Invalid post URL {0}")]
+    InvalidUrl(#[from] url::ParseError),
+}
+
+impl BlogPostSummary {
+    /// Summarise the post at `url` from its HTML. Relative image paths are
+    /// resolved against `url`.
+    pub fn parse(html: &str, url: &str) -> Result<Self, BlogPostParseError> {
+        let post_url = Url::parse(url)?;
+        let document = Html::parse_fragment(html);
+
+        let title = select_text(&document, "h2")
+            .filter(|t| !t.is_empty())
+            .ok_or(BlogPostParseError::MissingTitle)?;
+        let date = select_text(&document, ".date").filter(|d| !d.is_empty());
+        let excerpt = select_text(&document, ".blog-post-content p")
+            .or_else(|| select_text(&document, "p"))
+            .map(|p| truncate_at_word(&p, MAX_EXCERPT_CHARS))
+            .unwrap_or_default();
+
+        let content = select_text(&document, ".blog-post-content")
+            .unwrap_or_else(|| document.root_element().text().collect());
+        let n_words = content.split_whitespace().count();
+        let reading_time_minutes = n_words.div_ceil(WORDS_PER_MINUTE).max(1);
+
+        let hero_image = document
+            .select(&selector("img"))
+            .find_map(|img| img.value().attr("src"))
+            .and_then(|src| post_url.join(src).ok())
+            .map(String::from);
+
+        let tags = document
+            .select(&selector("meta[name=tags]"))
+            .filter_map(|meta| meta.value().attr("content"))
+            .flat_map(|content| content.split(','))
+            .map(|tag| tag.trim().to_lowercase())
+            .filter(|tag| !tag.is_empty())
+            .collect();
+
+        Ok(Self {
+            title,
+            date,
+            excerpt,
+            reading_time_minutes,
+            hero_image,
+            url: post_url.into(),
+            tags,
+        })
+    }
+}
+
+/// Record a post in `blog_posts` as published now. Returns false if it had
+/// already been published, in which case it shouldn't be announced again.
+#[tracing::instrument(skip_all)]
+pub async fn register_post<'a, T>(
+    executor: T,
+    slug: &str,
+    post: &BlogPostSummary,
+) -> Result<bool, sqlx::Error>
+where
+    T: Executor<'a, Database = Postgres>,
+{
+    let result = sqlx::query!(
+        r#"
+        INSERT INTO blog_posts
+            (slug, title, url, date, excerpt, reading_time_minutes, hero_image, tags)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        ON CONFLICT DO NOTHING
+        "#,
+        slug,
+        post.title,
+        post.url,
+        post.date,
+        post.excerpt,
+        i32::try_from(post.reading_time_minutes).unwrap_or(i32::MAX),
+        post.hero_image,
+        &post.tags
+    )
+    .execute(executor)
+    .await?;
+    Ok(result.rows_affected() == 1)
+}
+
+/// Posts published after `start`, up to and including `end`, oldest first.
+#[tracing::instrument(skip_all)]
+pub async fn published_between<'a, T>(
+    executor: T,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Result<Vec<BlogPostSummary>, sqlx::Error>
+where
+    T: Executor<'a, Database = Postgres>,
+{
+    let rows = sqlx::query!(
+        r#"
+        SELECT
+            title AS "title!",
+            url AS "url!",
+            date,
+            excerpt,
+            reading_time_minutes,
+            hero_image,
+            tags
+        FROM blog_posts
+        WHERE published_at > $1 AND published_at <= $2
+        AND title IS NOT NULL AND url IS NOT NULL
+        ORDER BY published_at
+        "#,
+        start,
+        end
+    )
+    .fetch_all(executor)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| BlogPostSummary {
+            title: row.title,
+            date: row.date,
+            excerpt: row.excerpt.unwrap_or_default(),
+            reading_time_minutes: row
+                .reading_time_minutes
+                .and_then(|m| usize::try_from(m).ok())
+                .unwrap_or(1),
+            hero_image: row.hero_image,
+            url: row.url,
+            tags: row.tags,
+        })
+        .collect())
+}
+
+fn selector(selector: &str) -> Selector {
+    Selector::parse(selector).expect("Invalid CSS selector")
+}
+
+/// Whitespace-normalised text of the first element matching `selector`.
+fn select_text(document: &Html, selector_str: &str) -> Option<String> {
+    document.select(&selector(selector_str)).next().map(|e| {
+        e.text()
+            .flat_map(str::split_whitespace)
+            .collect::<Vec<_>>()
+            .join(" ")
+    })
+}
+
+fn truncate_at_word(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        return text.to_string();
+    }
+    let mut truncated = String::new();
+    for word in text.split_whitespace() {
+        if truncated.chars().count() + word.chars().count() + 1 > max_chars {
+            break;
+        }
+        if !truncated.is_empty() {
+            truncated.push(' ');
+        }
+        truncated.push_str(word);
+    }
+    truncated.push('…');
+    truncated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{truncate_at_word, BlogPostParseError, BlogPostSummary};
+    use claims::assert_matches;
+
+    const POST: &str = r#"
+        <h2>A  Post
+            Title</h2>
+        <div class="date">January 23, 2025</div>
+        <meta name="tags" content="Rust, distributed-systems,">
+        <div class="blog-post-content">
+            <img src="/images/hero.png" alt="">
+            <p>First   paragraph.</p>
+            <p>Second paragraph.</p>
+        </div>
+    "#;
+
+    #[test]
+    fn summary_is_read_from_post_html() {
+        let summary = BlogPostSummary::parse(POST, "https://tld.com/blog/a-post").unwrap();
+        assert_eq!(summary.title, "A Post Title");
+        assert_eq!(summary.date.as_deref(), Some("January 23, 2025"));
+        assert_eq!(summary.excerpt, "First paragraph.");
+        assert_eq!(summary.reading_time_minutes, 1);
+        assert_eq!(
+            summary.hero_image.as_deref(),
+            Some("https://tld.com/images/hero.png")
+        );
+        assert_eq!(summary.url, "https://tld.com/blog/a-post");
+        assert_eq!(summary.tags, vec!["rust", "distributed-systems"]);
+    }
+
+    #[test]
+    fn post_without_title_is_rejected() {
+        let result = BlogPostSummary::parse("<p>No title</p>", "https://tld.com/blog/x");
+        assert_matches!(result, Err(BlogPostParseError::MissingTitle));
+    }
+
+    #[test]
+    fn optional_parts_may_be_missing() {
+        let summary = BlogPostSummary::parse("<h2>Title</h2>", "https://tld.com/blog/x").unwrap();
+        assert_eq!(summary.date, None);
+        assert_eq!(summary.excerpt, "");
+        assert_eq!(summary.hero_image, None);
+        assert!(summary.tags.is_empty());
+    }
+
+    #[test]
+    fn reading_time_rounds_up() {
+        let words = "word ".repeat(401);
+        let html = format!(
+            "<h2>Title</h2><div class=\"blog-post-content\"><p>{}</p></div>",
+            words
+        );
+        let summary = BlogPostSummary::parse(&html, "https://tld.com/blog/x").unwrap();
+        assert_eq!(summary.reading_time_minutes, 3);
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn truncated_excerpt_never_exceeds_limit(text: String, max_chars: u8) -> bool {
+        let max_chars = usize::from(max_chars);
+        // Allow for the ellipsis
+        truncate_at_word(&text, max_chars).chars().count() <= max_chars + 1
+    }
+}
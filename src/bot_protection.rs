@@ -0,0 +1,315 @@
+use crate::signed_token::HmacSigner;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64, Engine};
+use chrono::{DateTime, TimeDelta, Utc};
+use rand::{thread_rng, RngCore};
+use secrecy::Secret;
+use sha2::{Digest, Sha256};
+
+/// Why a sign-up was taken to come from a bot.
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum BotCheckFailure {
+    #[error("Honeypot field was filled in")]
+    Honeypot,
+    #[error("Form token is missing, malformed or not signed by us")]
+    InvalidToken,
+    #[error("Form was submitted too soon after it was served")]
+    TooFast,
+    #[error("Form token has expired")]
+    Expired,
+    #[error("Proof of work is missing or incorrect")]
+    InvalidProofOfWork,
+}
+
+/// The bot protection fields of a submitted sign-up form.
+#[derive(Debug, Clone, Copy)]
+pub struct Submission<'a> {
+    /// A field hidden from people, which bots tend to fill in
+    pub honeypot: &'a str,
+    /// The token served with the form
+    pub form_token: &'a str,
+    /// The counter found by the browser, if proof of work is enabled
+    pub proof_of_work: &'a str,
+    /// The address being signed up, which the proof of work is bound to
+    pub email: &'a str,
+    /// CSRF token of the session submitting the form, which the form token
+    /// is bound to
+    pub session_token: &'a str,
+}
+
+/// What the subscription form needs to be submitted successfully.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct FormChallenge {
+    pub form_token: String,
+    /// Leading zero bits required of the proof of work hash, if one is
+    /// required at all
+    pub pow_difficulty: Option<u8>,
+}
+
+/// Keeps bots off the subscription form without a third-party captcha.
+///
+/// Each form is served with a signed token recording when it was served, and
+/// sign-ups are rejected if it comes back sooner than a person could fill the
+/// form in, or later than `max_age`. The token is bound to the visitor's
+/// session, so one token can't be shared between many bots. Optionally the browser must also find a
+/// counter such that `SHA-256("<form token>:<email>:<counter>")` starts with
+/// `pow_difficulty` zero bits, which costs a person a moment but makes mass
+/// sign-ups expensive. Including the address means a solution can't be
+/// replayed to sign up other addresses with the same token.
+pub struct BotProtection {
+    signer: HmacSigner,
+    min_fill_time: TimeDelta,
+    max_age: TimeDelta,
+    pow_difficulty: Option<u8>,
+}
+
+impl BotProtection {
+    pub fn new(secret: &Secret<String>) -> Self {
+        Self {
+            signer: HmacSigner::new(secret, "signup-form-v1"),
+            min_fill_time: TimeDelta::seconds(3),
+            max_age: TimeDelta::hours(2),
+            pow_difficulty: None,
+        }
+    }
+
+    /// Override how soon after the form is served a sign-up is accepted.
+    pub fn min_fill_seconds(mut self, seconds: Option<i64>) -> Self {
+        if let Some(seconds) = seconds {
+            self.min_fill_time = TimeDelta::seconds(seconds);
+        }
+        self
+    }
+
+    /// Require a proof of work with `difficulty` leading zero bits. Each extra
+    /// bit doubles the work the browser has to do.
+    pub fn proof_of_work(mut self, difficulty: Option<u8>) -> Self {
+        self.pow_difficulty = difficulty.filter(|&d| d > 0);
+        self
+    }
+
+    /// A fresh challenge to serve with the form, to the session whose CSRF
+    /// token is `session_token`.
+    pub fn challenge(&self, session_token: &str) -> FormChallenge {
+        self.challenge_at(session_token, Utc::now())
+    }
+
+    fn challenge_at(&self, session_token: &str, now: DateTime<Utc>) -> FormChallenge {
+        let mut nonce = [0u8; 16];
+        thread_rng().fill_bytes(&mut nonce);
+        let form_token = self.signer.sign(
+            &[session_token],
+            &[&now.timestamp().to_string(), &BASE64.encode(nonce)],
+        );
+        FormChallenge {
+            form_token,
+            pow_difficulty: self.pow_difficulty,
+        }
+    }
+
+    /// Whether a submission looks like it came from a person using the form.
+    pub fn check(&self, submission: &Submission) -> Result<(), BotCheckFailure> {
+        self.check_at(submission, Utc::now())
+    }
+
+    fn check_at(&self, submission: &Submission, now: DateTime<Utc>) -> Result<(), BotCheckFailure> {
+        if !submission.honeypot.is_empty() {
+            return Err(BotCheckFailure::Honeypot);
+        }
+
+        let fields = self
+            .signer
+            .verify(submission.form_token, &[submission.session_token])
+            .map_err(|_| BotCheckFailure::InvalidToken)?;
+        let [issued_at, _nonce] = fields[..] else {
+            return Err(BotCheckFailure::InvalidToken);
+        };
+        let issued_at: i64 = issued_at
+            .parse()
+            .map_err(|_| BotCheckFailure::InvalidToken)?;
+
+        let elapsed = now.timestamp() - issued_at;
+        if elapsed < self.min_fill_time.num_seconds() {
+            return Err(BotCheckFailure::TooFast);
+        }
+        if elapsed > self.max_age.num_seconds() {
+            return Err(BotCheckFailure::Expired);
+        }
+
+        if let Some(difficulty) = self.pow_difficulty {
+            if !is_valid_proof_of_work(
+                submission.form_token,
+                submission.email,
+                submission.proof_of_work,
+                difficulty,
+            ) {
+                return Err(BotCheckFailure::InvalidProofOfWork);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut n = 0;
+    for byte in hash {
+        n += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    n
+}
+
+/// Whether `counter` solves the proof of work for `form_token` and `email`.
+/// Must agree with the script in `blog.html`.
+fn is_valid_proof_of_work(form_token: &str, email: &str, counter: &str, difficulty: u8) -> bool {
+    if counter.is_empty() || !counter.bytes().all(|b| b.is_ascii_digit()) {
+        return false;
+    }
+    let hash = Sha256::digest(format!("{}:{}:{}", form_token, email, counter).as_bytes());
+    leading_zero_bits(&hash) >= u32::from(difficulty)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_valid_proof_of_work, BotCheckFailure, BotProtection, Submission};
+    use chrono::{TimeDelta, Utc};
+    use claims::{assert_err_eq, assert_ok};
+    use secrecy::Secret;
+
+    fn solve(form_token: &str, email: &str, difficulty: u8) -> String {
+        (0u64..)
+            .map(|n| n.to_string())
+            .find(|n| is_valid_proof_of_work(form_token, email, n, difficulty))
+            .unwrap()
+    }
+
+    fn protection(secret: &str) -> BotProtection {
+        BotProtection::new(&Secret::new(secret.to_string()))
+    }
+
+    fn submission(form_token: &str) -> Submission<'_> {
+        Submission {
+            honeypot: "",
+            form_token,
+            proof_of_work: "",
+            email: "someone@example.com",
+            session_token: "session",
+        }
+    }
+
+    #[test]
+    fn form_filled_in_by_a_person_is_accepted() {
+        let protection = protection("secret");
+        let now = Utc::now();
+        let challenge = protection.challenge_at("session", now - TimeDelta::seconds(10));
+        assert_ok!(protection.check_at(&submission(&challenge.form_token), now));
+    }
+
+    #[test]
+    fn filled_honeypot_is_rejected() {
+        let protection = protection("secret");
+        let now = Utc::now();
+        let challenge = protection.challenge_at("session", now - TimeDelta::seconds(10));
+        let submission = Submission {
+            honeypot: "https://spam.example.com",
+            ..submission(&challenge.form_token)
+        };
+        assert_err_eq!(
+            protection.check_at(&submission, now),
+            BotCheckFailure::Honeypot
+        );
+    }
+
+    #[test]
+    fn instant_and_stale_submissions_are_rejected() {
+        let protection = protection("secret");
+        let now = Utc::now();
+        let challenge = protection.challenge_at("session", now);
+        assert_err_eq!(
+            protection.check_at(&submission(&challenge.form_token), now),
+            BotCheckFailure::TooFast
+        );
+        let challenge = protection.challenge_at("session", now - TimeDelta::days(1));
+        assert_err_eq!(
+            protection.check_at(&submission(&challenge.form_token), now),
+            BotCheckFailure::Expired
+        );
+    }
+
+    #[test]
+    fn backdated_token_is_rejected() {
+        let now = Utc::now();
+        let challenge = protection("secret").challenge_at("session", now);
+        let (_, rest) = challenge.form_token.split_once('.').unwrap();
+        let backdated = format!("{}.{}", (now - TimeDelta::minutes(1)).timestamp(), rest);
+        assert_err_eq!(
+            protection("secret").check_at(&submission(&backdated), now),
+            BotCheckFailure::InvalidToken
+        );
+        let challenge = protection("other").challenge_at("session", now - TimeDelta::minutes(1));
+        assert_err_eq!(
+            protection("secret").check_at(&submission(&challenge.form_token), now),
+            BotCheckFailure::InvalidToken
+        );
+    }
+
+    #[test]
+    fn token_served_to_another_session_is_rejected() {
+        let protection = protection("secret");
+        let now = Utc::now();
+        let challenge = protection.challenge_at("other session", now - TimeDelta::seconds(10));
+        assert_err_eq!(
+            protection.check_at(&submission(&challenge.form_token), now),
+            BotCheckFailure::InvalidToken
+        );
+    }
+
+    #[test]
+    fn proof_of_work_is_required_when_enabled() {
+        let protection = protection("secret").proof_of_work(Some(8));
+        let now = Utc::now();
+        let challenge = protection.challenge_at("session", now - TimeDelta::seconds(10));
+        assert_eq!(challenge.pow_difficulty, Some(8));
+        assert_err_eq!(
+            protection.check_at(&submission(&challenge.form_token), now),
+            BotCheckFailure::InvalidProofOfWork
+        );
+
+        let counter = solve(&challenge.form_token, "someone@example.com", 8);
+        let solved = Submission {
+            proof_of_work: &counter,
+            ..submission(&challenge.form_token)
+        };
+        assert_ok!(protection.check_at(&solved, now));
+    }
+
+    #[test]
+    fn replayed_proof_of_work_is_rejected_for_another_address() {
+        let protection = protection("secret").proof_of_work(Some(8));
+        let now = Utc::now();
+        let challenge = protection.challenge_at("session", now - TimeDelta::seconds(10));
+        let counter = solve(&challenge.form_token, "someone@example.com", 8);
+        // Find an address the solution doesn't happen to work for too
+        let other = (0u64..)
+            .map(|n| format!("bot{}@example.com", n))
+            .find(|email| !is_valid_proof_of_work(&challenge.form_token, email, &counter, 8))
+            .unwrap();
+        let replayed = Submission {
+            proof_of_work: &counter,
+            email: &other,
+            ..submission(&challenge.form_token)
+        };
+        assert_err_eq!(
+            protection.check_at(&replayed, now),
+            BotCheckFailure::InvalidProofOfWork
+        );
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn garbage_is_rejected(form_token: String) -> bool {
+        protection("secret")
+            .check(&submission(&form_token))
+            .is_err()
+    }
+}
@@ -0,0 +1,285 @@
+mod report;
+mod unsubscribe_request;
+mod verp;
+
+pub use report::{parse_report, BounceKind, BounceReport};
+pub use unsubscribe_request::{
+    parse_unsubscribe_request, unsubscribe_request_subject, UnsubscribeRequest,
+};
+pub use verp::{verp_recipient, verp_return_path};
+
+use crate::{
+    signed_token::{TokenPurpose, TokenSigner},
+    subscribers::{end_subscription, remove_subscriber, SubscriptionStatus},
+    subscription_events::{record_event, NewSubscriptionEvent, SubscriptionEventKind},
+    suppressions::{suppress_address, SuppressionReason},
+};
+use lettre::Address;
+use sqlx::{PgPool, Postgres, Transaction};
+use tracing_log::log;
+use uuid::Uuid;
+
+/// Number of soft bounces within `SOFT_BOUNCE_WINDOW_DAYS` after which a
+/// subscriber is suppressed, e.g. because their mailbox has been full for weeks.
+const SOFT_BOUNCE_LIMIT: i64 = 3;
+const SOFT_BOUNCE_WINDOW_DAYS: f64 = 30.0;
+
+/// What became of a bounce report.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BounceOutcome {
+    /// The subscriber will no longer be sent email
+    Suppressed { subscriber_id: Uuid },
+    /// The bounce was recorded, but isn't enough to suppress the subscriber
+    Recorded { subscriber_id: Uuid },
+    /// The report has already been processed
+    Duplicate,
+    /// The report couldn't be traced back to a subscriber
+    UnknownRecipient,
+}
+
+/// Record a bounce report against the subscriber it is about, suppressing them
+/// after a hard bounce, a complaint, or repeated soft bounces.
+#[tracing::instrument(skip_all)]
+pub async fn process_bounce(
+    pool: &PgPool,
+    report: &BounceReport,
+    bounce_address: Option<&Address>,
+) -> Result<BounceOutcome, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+
+    let Some(subscriber_id) = find_subscriber(&mut transaction, report, bounce_address).await?
+    else {
+        // The recipient named in the report isn't trusted, as anyone can send
+        // a report naming any address
+        log::warn!(
+            "Skipping {} report {} which could not be traced to a subscriber \
+            (recipient named: {})",
+            report.kind,
+            report
+                .report_id
+                .as_deref()
+                .unwrap_or("without a Message-ID"),
+            report.recipient.as_deref().unwrap_or("none")
+        );
+        return Ok(BounceOutcome::UnknownRecipient);
+    };
+
+    let inserted = sqlx::query!(
+        r#"
+        INSERT INTO email_bounces (id, subscriber_id, kind, status, diagnostic, report_id)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        ON CONFLICT (report_id) DO NOTHING
+        "#,
+        Uuid::new_v4(),
+        subscriber_id,
+        report.kind as BounceKind,
+        report.status,
+        report.diagnostic,
+        report.report_id
+    )
+    .execute(&mut *transaction)
+    .await?;
+    if inserted.rows_affected() == 0 {
+        return Ok(BounceOutcome::Duplicate);
+    }
+
+    let suppress = match report.kind {
+        BounceKind::HardBounce | BounceKind::Complaint => true,
+        BounceKind::SoftBounce => {
+            let n_soft_bounces = sqlx::query_scalar!(
+                r#"
+                SELECT COUNT(*) AS "count!" FROM email_bounces
+                WHERE subscriber_id = $1
+                AND kind = 'soft_bounce'
+                AND received_at > NOW() - $2 * INTERVAL '1 day'
+                "#,
+                subscriber_id,
+                SOFT_BOUNCE_WINDOW_DAYS
+            )
+            .fetch_one(&mut *transaction)
+            .await?;
+            n_soft_bounces >= SOFT_BOUNCE_LIMIT
+        }
+    };
+
+    let outcome = if suppress {
+        suppress_subscriber(
+            &mut transaction,
+            subscriber_id,
+            report.kind.into(),
+            &suppression_reason(report),
+        )
+        .await?;
+        BounceOutcome::Suppressed { subscriber_id }
+    } else {
+        BounceOutcome::Recorded { subscriber_id }
+    };
+    transaction.commit().await?;
+    Ok(outcome)
+}
+
+/// What became of an emailed unsubscribe request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UnsubscribeOutcome {
+    Unsubscribed {
+        subscriber_id: Uuid,
+    },
+    /// The token was forged, expired or not an unsubscribe token
+    InvalidToken,
+}
+
+/// Act on an unsubscribe request sent to the `mailto:` URI of a
+/// `List-Unsubscribe` header. Requests are identified by their token, so
+/// processing one twice is harmless.
+#[tracing::instrument(skip_all)]
+pub async fn process_unsubscribe_request(
+    pool: &PgPool,
+    token_signer: &TokenSigner,
+    request: &UnsubscribeRequest,
+) -> Result<UnsubscribeOutcome, sqlx::Error> {
+    let Ok(subscriber_id) = token_signer.verify(&request.token, TokenPurpose::Unsubscribe) else {
+        return Ok(UnsubscribeOutcome::InvalidToken);
+    };
+    let mut event = NewSubscriptionEvent::new(SubscriptionEventKind::Unsubscribed)
+        .source("list-unsubscribe-mailto");
+    if let Some(sender) = &request.sender {
+        event = event.detail(format!("Requested by email from {}", sender));
+    }
+    remove_subscriber(pool, subscriber_id, &event).await?;
+    Ok(UnsubscribeOutcome::Unsubscribed { subscriber_id })
+}
+
+/// Trace a report back to a subscriber by its VERP return path or the
+/// Message-ID of the bounced message.
+async fn find_subscriber(
+    transaction: &mut Transaction<'_, Postgres>,
+    report: &BounceReport,
+    bounce_address: Option<&Address>,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let verp_recipient = bounce_address.and_then(|bounce_address| {
+        report
+            .delivered_to
+            .iter()
+            .find_map(|address| verp_recipient(bounce_address, address))
+    });
+    if let Some(email) = verp_recipient {
+        if let Some(id) = subscriber_with_email(transaction, &email).await? {
+            return Ok(Some(id));
+        }
+    }
+
+    if let Some(message_id) = &report.original_message_id {
+        let logged = sqlx::query_scalar!(
+            r#"SELECT subscriber_id FROM email_delivery_log WHERE message_id = $1"#,
+            message_id
+        )
+        .fetch_optional(&mut **transaction)
+        .await?;
+        if logged.is_some() {
+            return Ok(logged);
+        }
+    }
+
+    Ok(None)
+}
+
+async fn subscriber_with_email(
+    transaction: &mut Transaction<'_, Postgres>,
+    email: &str,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"SELECT id FROM subscriptions WHERE lower(email) = lower($1)"#,
+        email
+    )
+    .fetch_optional(&mut **transaction)
+    .await
+}
+
+fn suppression_reason(report: &BounceReport) -> String {
+    let details: Vec<&str> = [report.status.as_deref(), report.diagnostic.as_deref()]
+        .into_iter()
+        .flatten()
+        .collect();
+    if details.is_empty() {
+        report.kind.to_string()
+    } else {
+        format!("{}: {}", report.kind, details.join(" "))
+    }
+}
+
+/// Stop sending email to a subscriber by adding their address to the
+/// suppression list and ending their subscription, which cancels anything
+/// queued for them. A complaint counts as unsubscribing.
+#[tracing::instrument(skip_all)]
+pub async fn suppress_subscriber(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    reason: SuppressionReason,
+    detail: &str,
+) -> Result<(), sqlx::Error> {
+    let email = sqlx::query_scalar!(
+        r#"SELECT email FROM subscriptions WHERE id = $1"#,
+        subscriber_id
+    )
+    .fetch_optional(&mut **transaction)
+    .await?;
+    if let Some(email) = email {
+        suppress_address(&mut **transaction, &email, reason, Some(detail)).await?;
+    }
+
+    let (status, kind) = match reason {
+        SuppressionReason::Bounced => (SubscriptionStatus::Bounced, SubscriptionEventKind::Bounced),
+        _ => (
+            SubscriptionStatus::Unsubscribed,
+            SubscriptionEventKind::Unsubscribed,
+        ),
+    };
+    if end_subscription(transaction, subscriber_id, status)
+        .await?
+        .is_some()
+    {
+        let event = NewSubscriptionEvent::new(kind)
+            .source("bounce-report")
+            .detail(detail);
+        record_event(&mut **transaction, subscriber_id, &event).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{suppression_reason, BounceKind, BounceReport};
+
+    fn report(kind: BounceKind) -> BounceReport {
+        BounceReport {
+            kind,
+            report_id: None,
+            delivered_to: vec![],
+            recipient: None,
+            original_message_id: None,
+            status: None,
+            diagnostic: None,
+        }
+    }
+
+    #[test]
+    fn suppression_reason_includes_status_and_diagnostic() {
+        let report = BounceReport {
+            status: Some("5.1.1".into()),
+            diagnostic: Some("smtp; 550 No such user".into()),
+            ..report(BounceKind::HardBounce)
+        };
+        assert_eq!(
+            suppression_reason(&report),
+            "hard bounce: 5.1.1 smtp; 550 No such user"
+        );
+    }
+
+    #[test]
+    fn suppression_reason_for_complaint_is_its_kind() {
+        assert_eq!(
+            suppression_reason(&report(BounceKind::Complaint)),
+            "complaint"
+        );
+    }
+}
@@ -0,0 +1,286 @@
+use mail_parser::{Message, MessageParser, MimeHeaders};
+
+/// What a bounce report says happened to a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[sqlx(type_name = "email_bounce_kind", rename_all = "snake_case")]
+pub enum BounceKind {
+    /// Permanent failure, e.g. the mailbox doesn't exist
+    HardBounce,
+    /// Temporary failure, e.g. the mailbox is full
+    SoftBounce,
+    /// The recipient marked the message as spam
+    Complaint,
+}
+
+impl std::fmt::Display for BounceKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::HardBounce => write!(f, "hard bounce"),
+            Self::SoftBounce => write!(f, "soft bounce"),
+            Self::Complaint => write!(f, "complaint"),
+        }
+    }
+}
+
+/// A delivery status notification (RFC 3464) or abuse report (RFC 5965) about
+/// a message we sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BounceReport {
+    pub kind: BounceKind,
+    /// Message-ID of the report itself, used to ignore duplicates
+    pub report_id: Option<String>,
+    /// Addresses the report was delivered to. With VERP return paths these
+    /// encode the original recipient.
+    pub delivered_to: Vec<String>,
+    /// Recipient named in the report
+    pub recipient: Option<String>,
+    /// Message-ID of the message which bounced
+    pub original_message_id: Option<String>,
+    /// Enhanced status code, e.g. `5.1.1`
+    pub status: Option<String>,
+    pub diagnostic: Option<String>,
+}
+
+/// Parse a raw email as a bounce report. Returns `None` for anything else,
+/// including reports of successful delivery.
+pub fn parse_report(raw: &[u8]) -> Option<BounceReport> {
+    let message = MessageParser::default().parse(raw)?;
+
+    let mut delivery_status = None;
+    let mut feedback_report = None;
+    let mut original_message_id = None;
+    for part in &message.parts {
+        let Some(content_type) = part.content_type() else {
+            continue;
+        };
+        let subtype = content_type.subtype().unwrap_or_default();
+        match (content_type.ctype(), subtype) {
+            ("message", "delivery-status" | "global-delivery-status") => {
+                delivery_status = part.text_contents().map(parse_fields)
+            }
+            ("message", "feedback-report") => {
+                feedback_report = part.text_contents().map(parse_fields)
+            }
+            ("message", "rfc822" | "global") => {
+                original_message_id = part
+                    .message()
+                    .and_then(Message::message_id)
+                    .map(String::from)
+            }
+            ("text", "rfc822-headers") => {
+                original_message_id = MessageParser::default()
+                    .parse_headers(part.contents())
+                    .and_then(|headers| headers.message_id().map(String::from))
+            }
+            _ => {}
+        }
+    }
+
+    let (kind, recipient, status, diagnostic) = if let Some(fields) = feedback_report {
+        // Providers also send reports for mail marked as "not spam"
+        if field(&fields, "feedback-type").is_some_and(|t| t.eq_ignore_ascii_case("not-spam")) {
+            return None;
+        }
+        let recipient = field(&fields, "original-rcpt-to").map(address_value);
+        (BounceKind::Complaint, recipient, None, None)
+    } else if let Some(fields) = delivery_status {
+        let status = field(&fields, "status").map(String::from);
+        let kind = match (field(&fields, "action"), status.as_deref()) {
+            (Some(action), _) if action.eq_ignore_ascii_case("delayed") => BounceKind::SoftBounce,
+            (Some(action), Some(status))
+                if action.eq_ignore_ascii_case("failed") && status.starts_with('4') =>
+            {
+                BounceKind::SoftBounce
+            }
+            (Some(action), _) if action.eq_ignore_ascii_case("failed") => BounceKind::HardBounce,
+            // Successful delivery, relaying etc.
+            _ => return None,
+        };
+        let recipient = field(&fields, "final-recipient")
+            .or_else(|| field(&fields, "original-recipient"))
+            .map(address_value);
+        let diagnostic = field(&fields, "diagnostic-code").map(String::from);
+        (kind, recipient, status, diagnostic)
+    } else {
+        return None;
+    };
+
+    let mut delivered_to: Vec<String> = ["Delivered-To", "X-Original-To"]
+        .into_iter()
+        .filter_map(|name| message.header_raw(name))
+        .map(address_value)
+        .collect();
+    if let Some(to) = message.to() {
+        delivered_to.extend(to.iter().filter_map(|a| a.address()).map(String::from));
+    }
+
+    Some(BounceReport {
+        kind,
+        report_id: message.message_id().map(String::from),
+        delivered_to,
+        recipient,
+        original_message_id,
+        status,
+        diagnostic,
+    })
+}
+
+/// Parse the header-style fields of a delivery-status or feedback-report part.
+/// Field names are lowercased.
+fn parse_fields(text: &str) -> Vec<(String, String)> {
+    let mut fields: Vec<(String, String)> = vec![];
+    for line in text.lines() {
+        if line.starts_with([' ', '\t']) {
+            // Continuation of a folded field
+            if let Some((_, value)) = fields.last_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+        } else if let Some((name, value)) = line.split_once(':') {
+            fields.push((name.trim().to_lowercase(), value.trim().to_string()));
+        }
+    }
+    fields
+}
+
+fn field<'a>(fields: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    fields
+        .iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, v)| v.as_str())
+}
+
+/// The address in a field such as `rfc822; <reader@tld.com>`.
+fn address_value(value: &str) -> String {
+    let address = value.rsplit_once(';').map_or(value, |(_, a)| a);
+    address
+        .trim()
+        .trim_start_matches('<')
+        .trim_end_matches('>')
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_report, BounceKind};
+    use claims::assert_none;
+
+    fn dsn(action: &str, status: &str) -> String {
+        format!(
+            "From: MAILER-DAEMON@mx.tld.com\r\n\
+             To: bounces+reader=tld.com@blog.com\r\n\
+             Subject: Undelivered Mail Returned to Sender\r\n\
+             Message-ID: <report-1@mx.tld.com>\r\n\
+             MIME-Version: 1.0\r\n\
+             Content-Type: multipart/report; report-type=delivery-status;\r\n\
+             \tboundary=\"BOUNDARY\"\r\n\
+             \r\n\
+             --BOUNDARY\r\n\
+             Content-Type: text/plain\r\n\
+             \r\n\
+             Your message could not be delivered.\r\n\
+             --BOUNDARY\r\n\
+             Content-Type: message/delivery-status\r\n\
+             \r\n\
+             Reporting-MTA: dns; mx.tld.com\r\n\
+             \r\n\
+             Final-Recipient: rfc822; reader@tld.com\r\n\
+             Action: {action}\r\n\
+             Status: {status}\r\n\
+             Diagnostic-Code: smtp; 550 5.1.1 <reader@tld.com>:\r\n \
+             Recipient address rejected\r\n\
+             --BOUNDARY\r\n\
+             Content-Type: text/rfc822-headers\r\n\
+             \r\n\
+             From: blog@blog.com\r\n\
+             To: reader@tld.com\r\n\
+             Message-ID: <original-1@blog.com>\r\n\
+             Subject: New post\r\n\
+             --BOUNDARY--\r\n"
+        )
+    }
+
+    const COMPLAINT: &str = "From: feedback@isp.com\r\n\
+        To: bounces@blog.com\r\n\
+        Message-ID: <complaint-1@isp.com>\r\n\
+        MIME-Version: 1.0\r\n\
+        Content-Type: multipart/report; report-type=feedback-report; boundary=\"B\"\r\n\
+        \r\n\
+        --B\r\n\
+        Content-Type: text/plain\r\n\
+        \r\n\
+        This is an abuse report.\r\n\
+        --B\r\n\
+        Content-Type: message/feedback-report\r\n\
+        \r\n\
+        Feedback-Type: abuse\r\n\
+        User-Agent: isp-fbl/1.0\r\n\
+        Version: 1\r\n\
+        Original-Rcpt-To: <reader@isp.com>\r\n\
+        --B\r\n\
+        Content-Type: message/rfc822\r\n\
+        \r\n\
+        From: blog@blog.com\r\n\
+        To: reader@isp.com\r\n\
+        Message-ID: <original-2@blog.com>\r\n\
+        Subject: New post\r\n\
+        \r\n\
+        Hello\r\n\
+        --B--\r\n";
+
+    #[test]
+    fn permanent_failure_is_a_hard_bounce() {
+        let report = parse_report(dsn("failed", "5.1.1").as_bytes()).unwrap();
+        assert_eq!(report.kind, BounceKind::HardBounce);
+        assert_eq!(report.report_id.as_deref(), Some("report-1@mx.tld.com"));
+        assert_eq!(report.recipient.as_deref(), Some("reader@tld.com"));
+        assert_eq!(
+            report.original_message_id.as_deref(),
+            Some("original-1@blog.com")
+        );
+        assert_eq!(report.status.as_deref(), Some("5.1.1"));
+        assert!(report
+            .diagnostic
+            .unwrap()
+            .ends_with("Recipient address rejected"));
+        assert!(report
+            .delivered_to
+            .contains(&"bounces+reader=tld.com@blog.com".to_string()));
+    }
+
+    #[test]
+    fn transient_failure_is_a_soft_bounce() {
+        let report = parse_report(dsn("failed", "4.2.2").as_bytes()).unwrap();
+        assert_eq!(report.kind, BounceKind::SoftBounce);
+        let report = parse_report(dsn("delayed", "4.4.1").as_bytes()).unwrap();
+        assert_eq!(report.kind, BounceKind::SoftBounce);
+    }
+
+    #[test]
+    fn successful_delivery_is_not_a_bounce() {
+        assert_none!(parse_report(dsn("delivered", "2.0.0").as_bytes()));
+    }
+
+    #[test]
+    fn abuse_report_is_a_complaint() {
+        let report = parse_report(COMPLAINT.as_bytes()).unwrap();
+        assert_eq!(report.kind, BounceKind::Complaint);
+        assert_eq!(report.recipient.as_deref(), Some("reader@isp.com"));
+        assert_eq!(
+            report.original_message_id.as_deref(),
+            Some("original-2@blog.com")
+        );
+    }
+
+    #[test]
+    fn not_spam_report_is_ignored() {
+        let report = COMPLAINT.replace("Feedback-Type: abuse", "Feedback-Type: not-spam");
+        assert_none!(parse_report(report.as_bytes()));
+    }
+
+    #[test]
+    fn ordinary_email_is_not_a_bounce() {
+        let email = "From: a@tld.com\r\nTo: b@tld.com\r\nSubject: Hi\r\n\r\nHello\r\n";
+        assert_none!(parse_report(email.as_bytes()));
+    }
+}
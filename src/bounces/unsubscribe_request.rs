@@ -0,0 +1,77 @@
+use mail_parser::MessageParser;
+
+/// Subject of an unsubscribe request sent via the `mailto:` URI in a
+/// `List-Unsubscribe` header, carrying an unsubscribe token.
+pub fn unsubscribe_request_subject(token: &str) -> String {
+    format!("unsubscribe {}", token)
+}
+
+/// An email asking to unsubscribe, sent by a mail client on the subscriber's
+/// behalf.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnsubscribeRequest {
+    /// Unsubscribe token from the subject
+    pub token: String,
+    /// Address the request was sent from
+    pub sender: Option<String>,
+}
+
+/// Parse a raw email as an unsubscribe request, i.e. one whose subject was
+/// created by `unsubscribe_request_subject`. Returns `None` for anything else.
+pub fn parse_unsubscribe_request(raw: &[u8]) -> Option<UnsubscribeRequest> {
+    let message = MessageParser::default().parse_headers(raw)?;
+    let mut words = message.subject()?.split_whitespace();
+    let (Some(keyword), Some(token), None) = (words.next(), words.next(), words.next()) else {
+        return None;
+    };
+    if !keyword.eq_ignore_ascii_case("unsubscribe") {
+        return None;
+    }
+    let sender = message
+        .from()
+        .and_then(|from| from.first())
+        .and_then(|address| address.address())
+        .map(String::from);
+    Some(UnsubscribeRequest {
+        token: token.to_string(),
+        sender,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_unsubscribe_request, unsubscribe_request_subject};
+    use claims::{assert_none, assert_some};
+
+    fn email(subject: &str) -> String {
+        format!(
+            "From: Reader <reader@tld.com>\r\n\
+             To: bounces@blog.com\r\n\
+             Subject: {subject}\r\n\
+             Message-ID: <request-1@tld.com>\r\n\
+             \r\n\
+             This is an unsubscribe request.\r\n"
+        )
+    }
+
+    #[test]
+    fn request_carries_token_and_sender() {
+        let raw = email(&unsubscribe_request_subject("abc.123.sig"));
+        let request = assert_some!(parse_unsubscribe_request(raw.as_bytes()));
+        assert_eq!(request.token, "abc.123.sig");
+        assert_eq!(request.sender.as_deref(), Some("reader@tld.com"));
+    }
+
+    #[test]
+    fn keyword_is_case_insensitive() {
+        let raw = email("Unsubscribe abc.123.sig");
+        assert_some!(parse_unsubscribe_request(raw.as_bytes()));
+    }
+
+    #[test]
+    fn other_emails_are_not_requests() {
+        for subject in ["unsubscribe", "Re: unsubscribe abc.123.sig", "Hello there"] {
+            assert_none!(parse_unsubscribe_request(email(subject).as_bytes()));
+        }
+    }
+}
@@ -0,0 +1,68 @@
+use lettre::address::{Address, AddressError};
+
+/// Envelope sender encoding `recipient` in the style of VERP (Variable
+/// Envelope Return Paths), so that bounces identify who they are about. With a
+/// bounce address of `bounces@blog.com`, mail to `reader@tld.com` is sent from
+/// `bounces+reader=tld.com@blog.com`.
+pub fn verp_return_path(
+    bounce_address: &Address,
+    recipient: &str,
+) -> Result<Address, AddressError> {
+    let encoded = recipient.replacen('@', "=", 1);
+    Address::new(
+        format!("{}+{}", bounce_address.user(), encoded),
+        bounce_address.domain(),
+    )
+}
+
+/// The recipient encoded in a return path created by `verp_return_path`, if
+/// `return_path` is one.
+pub fn verp_recipient(bounce_address: &Address, return_path: &str) -> Option<String> {
+    let (user, domain) = return_path.rsplit_once('@')?;
+    if !domain.eq_ignore_ascii_case(bounce_address.domain()) {
+        return None;
+    }
+    let encoded = user.strip_prefix(&format!("{}+", bounce_address.user()))?;
+    let (local, domain) = encoded.rsplit_once('=')?;
+    if local.is_empty() || domain.is_empty() {
+        return None;
+    }
+    Some(format!("{}@{}", local, domain))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{verp_recipient, verp_return_path};
+    use crate::test_util::ValidEmailFixture;
+    use claims::assert_none;
+    use lettre::Address;
+
+    fn bounce_address() -> Address {
+        "bounces@blog.com".parse().unwrap()
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn recipient_can_be_recovered_from_return_path(recipient: ValidEmailFixture) -> bool {
+        let return_path = verp_return_path(&bounce_address(), &recipient.0).unwrap();
+        verp_recipient(&bounce_address(), return_path.as_ref()) == Some(recipient.0)
+    }
+
+    #[test]
+    fn return_path_encodes_recipient() {
+        let return_path = verp_return_path(&bounce_address(), "reader@tld.com").unwrap();
+        assert_eq!(return_path.to_string(), "bounces+reader=tld.com@blog.com");
+    }
+
+    #[test]
+    fn other_addresses_are_not_return_paths() {
+        assert_none!(verp_recipient(&bounce_address(), "bounces@blog.com"));
+        assert_none!(verp_recipient(
+            &bounce_address(),
+            "bounces+reader=tld.com@other.com"
+        ));
+        assert_none!(verp_recipient(
+            &bounce_address(),
+            "postmaster+reader=tld.com@blog.com"
+        ));
+    }
+}
@@ -0,0 +1,179 @@
+use crate::{ssr::SsrCommon, util::constant_time_eq};
+use actix_session::{Session, SessionExt};
+use actix_web::{
+    dev::Payload, error::ErrorBadRequest, error::InternalError, http::StatusCode, web, FromRequest,
+    HttpRequest, HttpResponse,
+};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use serde::de::DeserializeOwned;
+use std::future::Future;
+use std::pin::Pin;
+use tracing_log::log;
+
+const CSRF_SESSION_KEY: &str = "_csrf";
+
+/// Per-session tokens which forms must echo back in a `csrf_token` field, so
+/// that other sites can't submit forms on a visitor's behalf.
+pub trait Csrf {
+    /// The session's token, creating one if it doesn't have one yet.
+    fn csrf_token(&self) -> Result<String, actix_session::SessionInsertError>;
+    /// Whether `submitted` is the session's token.
+    fn verify_csrf(&self, submitted: &str) -> bool;
+}
+
+impl Csrf for Session {
+    fn csrf_token(&self) -> Result<String, actix_session::SessionInsertError> {
+        if let Some(token) = self.get::<String>(CSRF_SESSION_KEY).ok().flatten() {
+            return Ok(token);
+        }
+        let token = generate_csrf_token();
+        self.insert(CSRF_SESSION_KEY, &token)?;
+        Ok(token)
+    }
+
+    fn verify_csrf(&self, submitted: &str) -> bool {
+        match self.get::<String>(CSRF_SESSION_KEY).ok().flatten() {
+            Some(token) => constant_time_eq(token.as_bytes(), submitted.as_bytes()),
+            None => false,
+        }
+    }
+}
+
+/// A url-encoded form body whose `csrf_token` field matched the session's
+/// token. Use in place of `web::Form` for every form submitted from our own
+/// pages; mismatched submissions are rejected with an error page.
+pub struct CsrfForm<T>(pub T);
+
+#[derive(serde::Deserialize)]
+struct CsrfField {
+    #[serde(default)]
+    csrf_token: String,
+}
+
+impl<T> FromRequest for CsrfForm<T>
+where
+    T: DeserializeOwned + 'static,
+{
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        let body = web::Bytes::from_request(&req, payload);
+        Box::pin(async move {
+            let body = body.await?;
+            // `T` ignores the token field, and the token ignores the rest
+            let submitted: CsrfField =
+                serde_urlencoded::from_bytes(&body).map_err(ErrorBadRequest)?;
+            if !req.get_session().verify_csrf(&submitted.csrf_token) {
+                log::warn!("Rejecting form submission with invalid CSRF token");
+                return Err(rejection(&req));
+            }
+            let form = serde_urlencoded::from_bytes(&body).map_err(ErrorBadRequest)?;
+            Ok(CsrfForm(form))
+        })
+    }
+}
+
+fn rejection(req: &HttpRequest) -> actix_web::Error {
+    let response = req
+        .app_data::<web::Data<SsrCommon>>()
+        .and_then(|ssr| {
+            ssr.render_message(
+                StatusCode::FORBIDDEN,
+                "Session expired",
+                "Sorry - your session has expired. Please go back, reload the page and try again.",
+            )
+            .ok()
+        })
+        .unwrap_or_else(|| HttpResponse::new(StatusCode::FORBIDDEN));
+    InternalError::from_response("Invalid CSRF token", response).into()
+}
+
+fn generate_csrf_token() -> String {
+    let mut rng = thread_rng();
+    std::iter::repeat_with(|| rng.sample(Alphanumeric))
+        .map(char::from)
+        .take(32)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Csrf, CsrfForm};
+    use actix_session::{Session, SessionExt};
+    use actix_web::{http::StatusCode, test::TestRequest, FromRequest};
+
+    fn session() -> Session {
+        TestRequest::default().to_http_request().get_session()
+    }
+
+    #[test]
+    fn token_is_stable_within_a_session() {
+        let session = session();
+        let token = session.csrf_token().unwrap();
+        assert_eq!(session.csrf_token().unwrap(), token);
+        assert!(session.verify_csrf(&token));
+    }
+
+    #[test]
+    fn token_from_another_session_is_rejected() {
+        let token = session().csrf_token().unwrap();
+        let session = session();
+        session.csrf_token().unwrap();
+        assert!(!session.verify_csrf(&token));
+    }
+
+    #[test]
+    fn session_without_token_rejects_everything() {
+        let session = session();
+        assert!(!session.verify_csrf(""));
+        assert!(!session.verify_csrf("token"));
+    }
+
+    #[derive(serde::Deserialize)]
+    struct Form {
+        email: String,
+    }
+
+    async fn extract(
+        body: String,
+        with_session_token: bool,
+    ) -> Result<CsrfForm<Form>, actix_web::Error> {
+        let (request, mut payload) = TestRequest::post()
+            .insert_header(("Content-Type", "application/x-www-form-urlencoded"))
+            .set_payload(body)
+            .to_http_parts();
+        if with_session_token {
+            request.get_session().insert("_csrf", "token").unwrap();
+        }
+        CsrfForm::<Form>::from_request(&request, &mut payload).await
+    }
+
+    #[tokio::test]
+    async fn form_with_session_token_is_accepted() {
+        let form = extract("email=a%40example.com&csrf_token=token".into(), true)
+            .await
+            .unwrap();
+        assert_eq!(form.0.email, "a@example.com");
+    }
+
+    #[tokio::test]
+    async fn form_without_matching_token_is_forbidden() {
+        for (body, with_session_token) in [
+            ("email=a%40example.com", true),
+            ("email=a%40example.com&csrf_token=other", true),
+            ("email=a%40example.com&csrf_token=token", false),
+        ] {
+            let error = extract(body.into(), with_session_token)
+                .await
+                .err()
+                .unwrap();
+            assert_eq!(
+                error.as_response_error().status_code(),
+                StatusCode::FORBIDDEN
+            );
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use crate::{
+    blog_post::{self, BlogPostSummary},
+    email_campaigns, email_delivery_queue,
+    email_templates::EmailTemplates,
+};
+use chrono::{DateTime, TimeDelta, Utc};
+use sqlx::PgPool;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing_log::log;
+use uuid::Uuid;
+
+/// How often digests are sent.
+const DIGEST_PERIOD: TimeDelta = TimeDelta::weeks(1);
+
+/// How often the scheduler checks whether a digest is due. Digests go out at
+/// most this long after their period ends.
+const SCHEDULER_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How a subscriber hears about new posts.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Default, sqlx::Type, serde::Deserialize, serde::Serialize,
+)]
+#[sqlx(type_name = "delivery_mode", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryMode {
+    /// One email per post, sent when it is published
+    #[default]
+    Immediate,
+    /// One email a week listing the posts published that week
+    WeeklyDigest,
+}
+
+/// What became of a digest run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DigestOutcome {
+    /// The current period hasn't ended yet, or another instance has already
+    /// handled it
+    NotDue,
+    /// Nothing was published during the period, so nothing was sent
+    NoNewPosts,
+    Sent {
+        campaign_id: Uuid,
+        n_posts: usize,
+        n_queued: u64,
+    },
+}
+
+/// The period the next digest should cover, if it has ended by `now`. Each
+/// period starts where the last one ended; the first ends at `now`.
+fn digest_period(
+    last_period_end: Option<DateTime<Utc>>,
+    now: DateTime<Utc>,
+) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+    match last_period_end {
+        None => Some((now - DIGEST_PERIOD, now)),
+        Some(start) if start + DIGEST_PERIOD <= now => Some((start, now)),
+        Some(_) => None,
+    }
+}
+
+/// Tags for a digest of `posts`, so that it goes to anyone interested in at
+/// least one of them. If any post is untagged, the digest is for everyone.
+fn digest_tags(posts: &[BlogPostSummary]) -> Vec<String> {
+    if posts.iter().any(|post| post.tags.is_empty()) {
+        return vec![];
+    }
+    let mut tags: Vec<_> = posts.iter().flat_map(|post| post.tags.clone()).collect();
+    tags.sort();
+    tags.dedup();
+    tags
+}
+
+/// Send the digest for the period ending at `now`, if one is due, to every
+/// subscriber in `DeliveryMode::WeeklyDigest`.
+#[tracing::instrument(skip_all)]
+pub async fn run_digest(
+    pool: &PgPool,
+    email_templates: &EmailTemplates,
+    now: DateTime<Utc>,
+) -> Result<DigestOutcome, anyhow::Error> {
+    let mut transaction = pool.begin().await?;
+
+    let last_period_end = sqlx::query_scalar!(r#"SELECT MAX(period_end) FROM digest_runs"#)
+        .fetch_one(&mut *transaction)
+        .await?;
+    let Some((start, end)) = digest_period(last_period_end, now) else {
+        return Ok(DigestOutcome::NotDue);
+    };
+
+    let posts = blog_post::published_between(&mut *transaction, start, end).await?;
+
+    // Claim the period before sending anything. A concurrent run for the same
+    // period waits on the unique start and then inserts nothing.
+    let run_id = Uuid::new_v4();
+    let claimed = sqlx::query!(
+        r#"
+        INSERT INTO digest_runs (id, period_start, period_end, n_posts)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (period_start) DO NOTHING
+        "#,
+        run_id,
+        start,
+        end,
+        i32::try_from(posts.len()).unwrap_or(i32::MAX)
+    )
+    .execute(&mut *transaction)
+    .await?;
+    if claimed.rows_affected() == 0 {
+        return Ok(DigestOutcome::NotDue);
+    }
+
+    if posts.is_empty() {
+        transaction.commit().await?;
+        return Ok(DigestOutcome::NoNewPosts);
+    }
+
+    let content = email_templates.digest(&posts)?;
+    let name = format!("digest-{}", end.format("%Y-%m-%d"));
+    let campaign_id = email_campaigns::create_campaign(&mut *transaction, &name, &content).await?;
+    let n_queued = email_delivery_queue::push_campaign_tasks(
+        &mut *transaction,
+        campaign_id,
+        DeliveryMode::WeeklyDigest,
+        &digest_tags(&posts),
+    )
+    .await?;
+
+    sqlx::query!(
+        r#"UPDATE digest_runs SET campaign_id = $1 WHERE id = $2"#,
+        campaign_id,
+        run_id
+    )
+    .execute(&mut *transaction)
+    .await?;
+    email_delivery_queue::notify_workers(&mut *transaction).await?;
+    transaction.commit().await?;
+
+    Ok(DigestOutcome::Sent {
+        campaign_id,
+        n_posts: posts.len(),
+        n_queued,
+    })
+}
+
+/// Background task which sends a digest whenever one is due.
+pub async fn digest_scheduler(connection_pool: Arc<PgPool>, email_templates: Arc<EmailTemplates>) {
+    loop {
+        match run_digest(&connection_pool, &email_templates, Utc::now()).await {
+            Ok(DigestOutcome::NotDue) => log::debug!("No digest due"),
+            Ok(DigestOutcome::NoNewPosts) => log::info!("No new posts this week, digest skipped"),
+            Ok(DigestOutcome::Sent {
+                campaign_id,
+                n_posts,
+                n_queued,
+            }) => log::info!(
+                "Queued {} digest emails covering {} posts for campaign {}",
+                n_queued,
+                n_posts,
+                campaign_id
+            ),
+            Err(e) => log::error!("Error sending weekly digest: {:?}", e),
+        }
+        tokio::time::sleep(SCHEDULER_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{digest_period, digest_tags, DIGEST_PERIOD};
+    use crate::blog_post::BlogPostSummary;
+    use chrono::{TimeDelta, TimeZone, Utc};
+    use claims::assert_none;
+
+    fn post(tags: &[&str]) -> BlogPostSummary {
+        BlogPostSummary {
+            title: "Title".into(),
+            date: None,
+            excerpt: String::new(),
+            reading_time_minutes: 1,
+            hero_image: None,
+            url: "https://tld.com/blog/title".into(),
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn digest_is_tagged_with_every_post_tag() {
+        let posts = [post(&["rust", "career"]), post(&["rust"])];
+        assert_eq!(digest_tags(&posts), vec!["career", "rust"]);
+    }
+
+    #[test]
+    fn digest_with_untagged_post_is_for_everyone() {
+        let posts = [post(&["rust"]), post(&[])];
+        assert!(digest_tags(&posts).is_empty());
+    }
+
+    #[test]
+    fn first_digest_covers_the_past_week() {
+        let now = Utc.with_ymd_and_hms(2025, 2, 3, 9, 0, 0).unwrap();
+        assert_eq!(digest_period(None, now), Some((now - DIGEST_PERIOD, now)));
+    }
+
+    #[test]
+    fn digest_is_not_due_until_a_week_after_the_last() {
+        let last = Utc.with_ymd_and_hms(2025, 2, 3, 9, 0, 0).unwrap();
+        let now = last + DIGEST_PERIOD - TimeDelta::minutes(1);
+        assert_none!(digest_period(Some(last), now));
+    }
+
+    #[test]
+    fn late_digest_starts_where_the_last_ended() {
+        let last = Utc.with_ymd_and_hms(2025, 2, 3, 9, 0, 0).unwrap();
+        let now = last + DIGEST_PERIOD + TimeDelta::hours(5);
+        // Nothing published while the scheduler was down is missed
+        assert_eq!(digest_period(Some(last), now), Some((last, now)));
+    }
+}
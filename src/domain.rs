@@ -32,22 +32,8 @@ impl AsRef<str> for SubscriberEmail {
 #[cfg(test)]
 mod tests {
     use super::SubscriberEmail;
+    use crate::test_util::ValidEmailFixture;
     use claims::assert_err;
-    use fake::faker::internet::en::SafeEmail;
-    use fake::Fake;
-    use rand::rngs::StdRng;
-    use rand::SeedableRng;
-
-    #[derive(Debug, Clone)]
-    struct ValidEmailFixture(pub String);
-
-    impl quickcheck::Arbitrary for ValidEmailFixture {
-        fn arbitrary<G: quickcheck::Gen>(g: &mut G) -> Self {
-            let mut rng = StdRng::seed_from_u64(u64::arbitrary(g));
-            let email = SafeEmail().fake_with_rng(&mut rng);
-            Self(email)
-        }
-    }
 
     #[quickcheck_macros::quickcheck]
     fn valid_emails_are_parsed_successfully(valid_email: ValidEmailFixture) -> bool {
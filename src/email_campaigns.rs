@@ -0,0 +1,219 @@
+use crate::email_templates::EmailContent;
+use chrono::{DateTime, Utc};
+use sqlx::{Executor, Postgres, Transaction};
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[sqlx(type_name = "email_campaign_status", rename_all = "lowercase")]
+pub enum CampaignStatus {
+    Active,
+    /// Queued emails are held back until the campaign is resumed
+    Paused,
+    /// Queued emails have been discarded
+    Cancelled,
+}
+
+impl std::fmt::Display for CampaignStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Active => write!(f, "active"),
+            Self::Paused => write!(f, "paused"),
+            Self::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+/// How far delivery of a campaign has got.
+#[derive(Debug)]
+pub struct CampaignProgress {
+    pub id: Uuid,
+    pub name: String,
+    pub status: CampaignStatus,
+    pub created_at: DateTime<Utc>,
+    /// Emails waiting to be sent, including those being retried
+    pub n_queued: i64,
+    pub n_sent: i32,
+    /// Queued emails whose delivery has failed at least once
+    pub n_failed: i64,
+    /// Emails which were given up on
+    pub n_dead_lettered: i64,
+}
+
+/// A campaign which has been sent to subscribers, listed in the newsletter
+/// archive.
+#[derive(Debug)]
+pub struct ArchivedCampaign {
+    pub id: Uuid,
+    pub subject: String,
+    pub preheader: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A campaign's content, for its "view in browser" page.
+#[derive(Debug)]
+pub struct ArchivedCampaignContent {
+    pub subject: String,
+    pub email_html: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn create_campaign<'a, T>(
+    executor: T,
+    name: &str,
+    content: &EmailContent,
+) -> Result<Uuid, sqlx::Error>
+where
+    T: Executor<'a, Database = Postgres>,
+{
+    let id = Uuid::new_v4();
+    sqlx::query!(
+        r#"
+        INSERT INTO email_campaigns (id, name, subject, preheader, email_html, email_text)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        "#,
+        id,
+        name,
+        content.subject,
+        content.preheader,
+        content.html,
+        content.text
+    )
+    .execute(executor)
+    .await?;
+    Ok(id)
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn campaign_progress<'a, T>(executor: T) -> Result<Vec<CampaignProgress>, sqlx::Error>
+where
+    T: Executor<'a, Database = Postgres>,
+{
+    sqlx::query_as!(
+        CampaignProgress,
+        r#"
+        SELECT
+            email_campaigns.id,
+            email_campaigns.name,
+            email_campaigns.status AS "status: CampaignStatus",
+            email_campaigns.created_at,
+            email_campaigns.n_sent,
+            (
+                SELECT COUNT(*) FROM email_delivery_queue
+                WHERE campaign_id = email_campaigns.id
+            ) AS "n_queued!",
+            (
+                SELECT COUNT(*) FROM email_delivery_queue
+                WHERE campaign_id = email_campaigns.id AND n_retries > 0
+            ) AS "n_failed!",
+            (
+                SELECT COUNT(*) FROM email_delivery_dead_letters
+                WHERE campaign_id = email_campaigns.id
+            ) AS "n_dead_lettered!"
+        FROM email_campaigns
+        ORDER BY email_campaigns.created_at DESC
+        "#
+    )
+    .fetch_all(executor)
+    .await
+}
+
+/// Pause or resume a campaign. Returns false if there is no such campaign or it
+/// has already been cancelled.
+#[tracing::instrument(skip_all)]
+pub async fn set_campaign_paused<'a, T>(
+    executor: T,
+    campaign_id: Uuid,
+    paused: bool,
+) -> Result<bool, sqlx::Error>
+where
+    T: Executor<'a, Database = Postgres>,
+{
+    let status = if paused {
+        CampaignStatus::Paused
+    } else {
+        CampaignStatus::Active
+    };
+    let result = sqlx::query!(
+        r#"
+        UPDATE email_campaigns
+        SET status = $1
+        WHERE id = $2 AND status != 'cancelled'
+        "#,
+        status as CampaignStatus,
+        campaign_id
+    )
+    .execute(executor)
+    .await?;
+    Ok(result.rows_affected() == 1)
+}
+
+/// Cancel a campaign, discarding any of its emails which haven't been sent yet.
+/// Returns the number of emails discarded, or None if there is no such campaign.
+#[tracing::instrument(skip_all)]
+pub async fn cancel_campaign(
+    transaction: &mut Transaction<'_, Postgres>,
+    campaign_id: Uuid,
+) -> Result<Option<u64>, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"UPDATE email_campaigns SET status = 'cancelled' WHERE id = $1"#,
+        campaign_id
+    )
+    .execute(&mut **transaction)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Ok(None);
+    }
+
+    let result = sqlx::query!(
+        r#"DELETE FROM email_delivery_queue WHERE campaign_id = $1"#,
+        campaign_id
+    )
+    .execute(&mut **transaction)
+    .await?;
+    Ok(Some(result.rows_affected()))
+}
+
+/// Campaigns which have reached at least one subscriber, newest first.
+/// Cancelled campaigns are left out.
+#[tracing::instrument(skip_all)]
+pub async fn archived_campaigns<'a, T>(executor: T) -> Result<Vec<ArchivedCampaign>, sqlx::Error>
+where
+    T: Executor<'a, Database = Postgres>,
+{
+    sqlx::query_as!(
+        ArchivedCampaign,
+        r#"
+        SELECT id, subject, preheader, created_at
+        FROM email_campaigns
+        WHERE n_sent > 0 AND status != 'cancelled'
+        ORDER BY created_at DESC
+        "#
+    )
+    .fetch_all(executor)
+    .await
+}
+
+/// The content of a campaign which has reached at least one subscriber. Unlike
+/// the archive index this includes cancelled campaigns, so that a "view in
+/// browser" link keeps working for anyone who was sent one.
+#[tracing::instrument(skip_all)]
+pub async fn archived_campaign<'a, T>(
+    executor: T,
+    campaign_id: Uuid,
+) -> Result<Option<ArchivedCampaignContent>, sqlx::Error>
+where
+    T: Executor<'a, Database = Postgres>,
+{
+    sqlx::query_as!(
+        ArchivedCampaignContent,
+        r#"
+        SELECT subject, email_html, created_at
+        FROM email_campaigns
+        WHERE id = $1 AND n_sent > 0
+        "#,
+        campaign_id
+    )
+    .fetch_optional(executor)
+    .await
+}
@@ -1,63 +1,221 @@
+use crate::{digest::DeliveryMode, email_templates::EmailContent};
 use chrono::{DateTime, Utc};
 use sqlx::{Executor, FromRow, Postgres};
+use std::time::Duration;
 use uuid::Uuid;
 
+/// Postgres channel on which a notification is sent whenever tasks are added to
+/// the queue, so that idle workers can wake up without polling.
+pub const EMAIL_QUEUE_CHANNEL: &str = "email_queue";
+
+/// Which delivery lane a task is queued in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[sqlx(type_name = "email_priority", rename_all = "lowercase")]
+pub enum TaskPriority {
+    /// Mail a subscriber is waiting on, e.g. a confirmation link
+    Transactional,
+    /// Mail sent to many subscribers at once, e.g. a new post announcement
+    Bulk,
+}
+
 #[derive(FromRow)]
 pub struct EmailDeliveryTask {
     pub id: Uuid,
     pub subscriber_id: Uuid,
+    pub priority: TaskPriority,
+    pub campaign_id: Option<Uuid>,
     pub email: String,
     pub subject: String,
+    pub preheader: String,
     pub email_html: String,
     pub email_text: String,
     pub created_at: DateTime<Utc>,
     pub n_retries: i32,
     pub send_after: DateTime<Utc>,
+    pub backoff_ms: Option<i64>,
+    /// The recipient's address was suppressed, or the subscriber left, after
+    /// the task was queued. Unsubscribed addresses are still sent
+    /// transactional email, so that they can confirm a new subscription.
+    pub suppressed: bool,
 }
 
 #[tracing::instrument(skip_all)]
 pub async fn push_task<'a, T>(
     executor: T,
     subscriber_id: Uuid,
-    subject: &str,
-    html_content: &str,
-    text_content: &str,
+    priority: TaskPriority,
+    content: &EmailContent,
+) -> Result<(), sqlx::Error>
+where
+    T: Executor<'a, Database = Postgres>,
+{
+    insert_task(executor, subscriber_id, None, priority, content).await
+}
+
+/// Queue an email for a subscriber, but addressed to `recipient` rather than
+/// their current address, e.g. to confirm an address they want to switch to.
+#[tracing::instrument(skip_all)]
+pub async fn push_task_to<'a, T>(
+    executor: T,
+    subscriber_id: Uuid,
+    recipient: &str,
+    priority: TaskPriority,
+    content: &EmailContent,
+) -> Result<(), sqlx::Error>
+where
+    T: Executor<'a, Database = Postgres>,
+{
+    insert_task(executor, subscriber_id, Some(recipient), priority, content).await
+}
+
+async fn insert_task<'a, T>(
+    executor: T,
+    subscriber_id: Uuid,
+    recipient: Option<&str>,
+    priority: TaskPriority,
+    content: &EmailContent,
 ) -> Result<(), sqlx::Error>
 where
     T: Executor<'a, Database = Postgres>,
 {
     let id = Uuid::new_v4();
+    // The notification is only delivered once the enclosing transaction commits
     let query = sqlx::query!(
         r#"
-        INSERT INTO email_delivery_queue
-            (id, subscriber_id, subject, email_html, email_text)
-        VALUES ($1, $2, $3, $4, $5)
+        WITH task AS (
+            INSERT INTO email_delivery_queue
+                (id, subscriber_id, recipient, priority, subject, preheader, email_html, email_text)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            RETURNING id
+        )
+        SELECT pg_notify($9, task.id::text) FROM task
         "#,
         id,
         subscriber_id,
-        subject,
-        html_content,
-        text_content
+        recipient,
+        priority as TaskPriority,
+        content.subject,
+        content.preheader,
+        content.html,
+        content.text,
+        EMAIL_QUEUE_CHANNEL
     );
     query.execute(executor).await?;
     Ok(())
 }
 
+/// Queue a campaign's email for every active subscriber with the given
+/// delivery mode who hasn't paused delivery and whose address isn't
+/// suppressed, returning the number of tasks queued. Subscribers who chose
+/// topics only get campaigns tagged with one of them; untagged campaigns go to
+/// everyone, as do campaigns for subscribers who haven't chosen any topics.
 #[tracing::instrument(skip_all)]
-pub async fn peek_task<'a, T>(executor: T) -> Result<Option<EmailDeliveryTask>, sqlx::Error>
+pub async fn push_campaign_tasks<'a, T>(
+    executor: T,
+    campaign_id: Uuid,
+    delivery_mode: DeliveryMode,
+    tags: &[String],
+) -> Result<u64, sqlx::Error>
+where
+    T: Executor<'a, Database = Postgres>,
+{
+    let result = sqlx::query!(
+        r#"
+        INSERT INTO email_delivery_queue (id, subscriber_id, campaign_id, priority)
+        SELECT gen_random_uuid(), id, $1, 'bulk'
+        FROM subscriptions
+        WHERE status = 'active'
+        AND delivery_mode = $2
+        AND (paused_until IS NULL OR paused_until <= NOW())
+        AND NOT EXISTS (
+            SELECT 1 FROM email_suppressions
+            WHERE address_hash = email_address_hash(subscriptions.email)
+        )
+        AND (
+            cardinality($3::text[]) = 0
+            OR NOT EXISTS (
+                SELECT 1 FROM subscription_topics
+                WHERE subscriber_id = subscriptions.id
+            )
+            OR EXISTS (
+                SELECT 1 FROM subscription_topics
+                WHERE subscriber_id = subscriptions.id AND topic = ANY($3)
+            )
+        )
+        "#,
+        campaign_id,
+        delivery_mode as DeliveryMode,
+        tags
+    )
+    .execute(executor)
+    .await?;
+    Ok(result.rows_affected())
+}
+
+/// Wake any listening workers, e.g. after bulk-inserting tasks directly.
+#[tracing::instrument(skip_all)]
+pub async fn notify_workers<'a, T>(executor: T) -> Result<(), sqlx::Error>
+where
+    T: Executor<'a, Database = Postgres>,
+{
+    sqlx::query!(r#"SELECT pg_notify($1, '')"#, EMAIL_QUEUE_CHANNEL)
+        .execute(executor)
+        .await?;
+    Ok(())
+}
+
+/// Lock the next task that is due for delivery, taking tasks from the
+/// `preferred` lane first if there are any.
+#[tracing::instrument(skip_all)]
+pub async fn peek_task<'a, T>(
+    executor: T,
+    preferred: TaskPriority,
+) -> Result<Option<EmailDeliveryTask>, sqlx::Error>
 where
     T: Executor<'a, Database = Postgres>,
 {
     sqlx::query_as::<_, EmailDeliveryTask>(
         r#"
-        SELECT email_delivery_queue.*, subscriptions.email
-        FROM email_delivery_queue JOIN subscriptions
+        SELECT
+            email_delivery_queue.id,
+            email_delivery_queue.subscriber_id,
+            email_delivery_queue.priority,
+            email_delivery_queue.campaign_id,
+            COALESCE(email_delivery_queue.recipient, subscriptions.email) AS email,
+            COALESCE(email_delivery_queue.subject, email_campaigns.subject) AS subject,
+            COALESCE(email_delivery_queue.preheader, email_campaigns.preheader, '') AS preheader,
+            COALESCE(email_delivery_queue.email_html, email_campaigns.email_html) AS email_html,
+            COALESCE(email_delivery_queue.email_text, email_campaigns.email_text) AS email_text,
+            email_delivery_queue.created_at,
+            email_delivery_queue.n_retries,
+            email_delivery_queue.send_after,
+            email_delivery_queue.backoff_ms,
+            (
+                subscriptions.status IN ('unsubscribed', 'bounced')
+                OR EXISTS (
+                    SELECT 1 FROM email_suppressions
+                    WHERE address_hash = email_address_hash(
+                        COALESCE(email_delivery_queue.recipient, subscriptions.email)
+                    )
+                    AND (reason <> 'unsubscribed' OR email_delivery_queue.priority = 'bulk')
+                )
+            ) AS suppressed
+        FROM email_delivery_queue
+        JOIN subscriptions
         ON email_delivery_queue.subscriber_id = subscriptions.id
+        LEFT JOIN email_campaigns
+        ON email_delivery_queue.campaign_id = email_campaigns.id
+        WHERE email_delivery_queue.send_after <= NOW()
+        AND (email_campaigns.id IS NULL OR email_campaigns.status = 'active')
+        ORDER BY
+            (email_delivery_queue.priority = $1) DESC,
+            email_delivery_queue.send_after
         FOR UPDATE of email_delivery_queue
         SKIP LOCKED
         LIMIT 1
         "#, // FOR UPDATE locks the rows
     )
+    .bind(preferred)
     .fetch_optional(executor)
     .await
 }
@@ -68,7 +226,15 @@ where
     T: Executor<'a, Database = Postgres>,
 {
     sqlx::query!(
-        r#" DELETE FROM email_delivery_queue WHERE id = $1"#,
+        r#"
+        WITH popped AS (
+            DELETE FROM email_delivery_queue WHERE id = $1
+            RETURNING campaign_id
+        )
+        UPDATE email_campaigns
+        SET n_sent = n_sent + 1
+        WHERE id IN (SELECT campaign_id FROM popped)
+        "#,
         task_id
     )
     .execute(executor)
@@ -76,31 +242,126 @@ where
     Ok(())
 }
 
+/// Record the Message-ID of a delivered email, so that bounces can be traced
+/// back to its recipient.
+#[tracing::instrument(skip_all)]
+pub async fn log_delivery<'a, T>(
+    executor: T,
+    message_id: &str,
+    subscriber_id: Uuid,
+    campaign_id: Option<Uuid>,
+) -> Result<(), sqlx::Error>
+where
+    T: Executor<'a, Database = Postgres>,
+{
+    sqlx::query!(
+        r#"
+        INSERT INTO email_delivery_log (message_id, subscriber_id, campaign_id)
+        VALUES ($1, $2, $3)
+        "#,
+        message_id,
+        subscriber_id,
+        campaign_id
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+/// Move a task which can never be delivered out of the queue.
+#[tracing::instrument(skip_all)]
+pub async fn dead_letter_task<'a, T>(
+    executor: T,
+    task_id: Uuid,
+    reason: &str,
+) -> Result<(), sqlx::Error>
+where
+    T: Executor<'a, Database = Postgres>,
+{
+    sqlx::query!(
+        r#"
+        WITH dead AS (
+            DELETE FROM email_delivery_queue WHERE id = $1
+            RETURNING *
+        )
+        INSERT INTO email_delivery_dead_letters
+            (id, subscriber_id, campaign_id, subject, preheader, email_html,
+             email_text, created_at, n_retries, reason)
+        SELECT id, subscriber_id, campaign_id, subject, preheader, email_html,
+            email_text, created_at, n_retries, $2
+        FROM dead
+        "#,
+        task_id,
+        reason
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+/// Move everything queued for a subscriber to the dead letters, e.g. when they
+/// unsubscribe, returning the number of tasks cancelled. Tasks locked by a
+/// worker mid-delivery are left for it to finish.
+#[tracing::instrument(skip_all)]
+pub async fn cancel_subscriber_tasks<'a, T>(
+    executor: T,
+    subscriber_id: Uuid,
+    reason: &str,
+) -> Result<u64, sqlx::Error>
+where
+    T: Executor<'a, Database = Postgres>,
+{
+    let result = sqlx::query!(
+        r#"
+        WITH cancelled AS (
+            DELETE FROM email_delivery_queue
+            WHERE id IN (
+                SELECT id FROM email_delivery_queue
+                WHERE subscriber_id = $1
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING *
+        )
+        INSERT INTO email_delivery_dead_letters
+            (id, subscriber_id, campaign_id, subject, preheader, email_html,
+             email_text, created_at, n_retries, reason)
+        SELECT id, subscriber_id, campaign_id, subject, preheader, email_html,
+            email_text, created_at, n_retries, $2
+        FROM cancelled
+        "#,
+        subscriber_id,
+        reason
+    )
+    .execute(executor)
+    .await?;
+    Ok(result.rows_affected())
+}
+
+/// Reschedule a failed task to be retried after `delay`.
 #[tracing::instrument(skip_all)]
 pub async fn deprioritise_task<'a, T>(
     executor: T,
     task_id: Uuid,
-    n_retries: i32,
+    delay: Duration,
 ) -> Result<(), sqlx::Error>
 where
     T: Executor<'a, Database = Postgres>,
 {
-    const BASE_RETRY_INTERVAL_SECS: u64 = 60;
-    let delay_secs = BASE_RETRY_INTERVAL_SECS * 2u64.pow(n_retries as u32);
-    let send_after = Utc::now() + chrono::Duration::seconds(delay_secs as i64);
+    let backoff_ms = i64::try_from(delay.as_millis()).unwrap_or(i64::MAX);
 
     sqlx::query!(
         r#"
-        UPDATE email_delivery_queue 
-        SET 
+        UPDATE email_delivery_queue
+        SET
             n_retries = n_retries + 1,
-            send_after = $1
-        WHERE id = $2
+            send_after = NOW() + $1 * INTERVAL '1 second',
+            backoff_ms = $2
+        WHERE id = $3
     "#,
-        send_after,
+        delay.as_secs_f64(),
+        backoff_ms,
         task_id
     )
-    .bind(task_id)
     .execute(executor)
     .await?;
 
@@ -0,0 +1,253 @@
+use crate::util::{read_env_or_panic, read_optional_env};
+use lettre::message::dkim::{
+    DkimCanonicalization, DkimCanonicalizationType, DkimConfig, DkimSigningAlgorithm,
+    DkimSigningKey,
+};
+use lettre::message::header::HeaderName;
+use std::path::PathBuf;
+
+/// Headers covered by the signature. Headers missing from a message are still
+/// listed, which stops them being added in transit.
+const SIGNED_HEADERS: [&str; 7] = [
+    "From",
+    "To",
+    "Subject",
+    "Date",
+    "MIME-Version",
+    "List-Unsubscribe",
+    "List-Unsubscribe-Post",
+];
+
+/// Where to find the DKIM private key and how to advertise it. Read from the
+/// `DKIM_*` env vars; signing is disabled if `DKIM_PRIVATE_KEY_FILE` is unset.
+#[derive(Debug, Clone)]
+pub struct DkimSettings {
+    /// An RSA key in PKCS#1 PEM format, or an Ed25519 key as the base64 of its
+    /// 32 secret bytes
+    pub private_key_file: PathBuf,
+    pub algorithm: DkimSigningAlgorithm,
+    /// Name of the DNS record holding the public key, i.e.
+    /// `<selector>._domainkey.<domain>`
+    pub selector: String,
+    pub domain: String,
+}
+
+impl DkimSettings {
+    pub fn from_env() -> Option<Self> {
+        let private_key_file = read_optional_env::<PathBuf>("DKIM_PRIVATE_KEY_FILE")?;
+        let algorithm = match std::env::var("DKIM_ALGORITHM").as_deref() {
+            Ok("rsa") | Err(_) => DkimSigningAlgorithm::Rsa,
+            Ok("ed25519") => DkimSigningAlgorithm::Ed25519,
+            Ok(other) => panic!("Unknown DKIM_ALGORITHM {}", other),
+        };
+        Some(Self {
+            private_key_file,
+            algorithm,
+            selector: read_env_or_panic("DKIM_SELECTOR"),
+            domain: read_env_or_panic("DKIM_DOMAIN"),
+        })
+    }
+
+    pub fn load(&self) -> Result<DkimConfig, anyhow::Error> {
+        let private_key = std::fs::read_to_string(&self.private_key_file)?;
+        signing_config(
+            &self.selector,
+            &self.domain,
+            private_key.trim(),
+            self.algorithm,
+        )
+    }
+}
+
+pub fn signing_config(
+    selector: &str,
+    domain: &str,
+    private_key: &str,
+    algorithm: DkimSigningAlgorithm,
+) -> Result<DkimConfig, anyhow::Error> {
+    let key = DkimSigningKey::new(private_key, algorithm)?;
+    let headers = SIGNED_HEADERS
+        .into_iter()
+        .map(HeaderName::new_from_ascii_str)
+        .collect();
+    // Relaxed canonicalization survives the header rewrapping and whitespace
+    // changes some relays make
+    let canonicalization = DkimCanonicalization {
+        header: DkimCanonicalizationType::Relaxed,
+        body: DkimCanonicalizationType::Relaxed,
+    };
+    Ok(DkimConfig::new(
+        selector.into(),
+        domain.into(),
+        key,
+        headers,
+        canonicalization,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::signing_config;
+    use crate::domain::SubscriberEmail;
+    use crate::email_delivery_worker::EmailClient;
+    use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+    use claims::assert_err;
+    use ed25519_dalek::{Signature, SigningKey, VerifyingKey};
+    use lettre::message::dkim::DkimSigningAlgorithm;
+    use lettre::transport::stub::AsyncStubTransport;
+    use sha2::{Digest, Sha256};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    const SECRET: [u8; 32] = [7; 32];
+
+    /// Relaxed header canonicalization from RFC 6376 section 3.4.2.
+    fn canonical_header(name: &str, value: &str) -> String {
+        let value = value.split_whitespace().collect::<Vec<_>>().join(" ");
+        format!("{}:{}", name.to_lowercase(), value)
+    }
+
+    /// Relaxed body canonicalization from RFC 6376 section 3.4.4.
+    fn canonical_body(body: &str) -> String {
+        let mut lines: Vec<String> = body
+            .split("\r\n")
+            .map(|line| {
+                let mut collapsed = String::new();
+                for (i, word) in line.split([' ', '\t']).enumerate() {
+                    if i > 0 && !collapsed.ends_with(' ') {
+                        collapsed.push(' ');
+                    }
+                    collapsed.push_str(word);
+                }
+                collapsed.trim_end().to_string()
+            })
+            .collect();
+        while lines.last().is_some_and(|l| l.is_empty()) {
+            lines.pop();
+        }
+        lines.iter().map(|l| format!("{}\r\n", l)).collect()
+    }
+
+    /// Verify a message's Ed25519 DKIM signature against `public_key`, as a
+    /// receiving server would after looking the key up in DNS.
+    fn verify(message: &str, public_key: &VerifyingKey) -> Result<(), String> {
+        let (head, body) = message.split_once("\r\n\r\n").ok_or("No body")?;
+        // Unfold headers into (name, value) pairs
+        let mut headers: Vec<(String, String)> = vec![];
+        for line in head.split("\r\n") {
+            if line.starts_with([' ', '\t']) {
+                headers.last_mut().ok_or("Bad header")?.1.push_str(line);
+            } else {
+                let (name, value) = line.split_once(':').ok_or("Bad header")?;
+                headers.push((name.into(), value.into()));
+            }
+        }
+
+        let signature = headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("DKIM-Signature"))
+            .map(|(_, value)| value.clone())
+            .ok_or("Message is not signed")?;
+        let tags: HashMap<&str, String> = signature
+            .split(';')
+            .filter_map(|tag| tag.split_once('='))
+            .map(|(k, v)| (k.trim(), v.split_whitespace().collect()))
+            .collect();
+        if tags["a"] != "ed25519-sha256" || tags["c"] != "relaxed/relaxed" {
+            return Err("Unexpected algorithm or canonicalization".into());
+        }
+
+        let body_hash = BASE64.encode(Sha256::digest(canonical_body(body)));
+        if body_hash != tags["bh"] {
+            return Err("Body hash mismatch".into());
+        }
+
+        let mut signed = String::new();
+        for name in tags["h"].split(':') {
+            if let Some((name, value)) = headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name))
+            {
+                signed.push_str(&canonical_header(name, value));
+                signed.push_str("\r\n");
+            }
+        }
+        // The signature header itself is signed with an empty b= tag, which
+        // comes last
+        let b_start = signature.rfind(';').ok_or("No b= tag")?;
+        let unsigned = format!("{} b=", &signature[..=b_start]);
+        signed.push_str(&canonical_header("DKIM-Signature", &unsigned));
+
+        let signature_bytes = BASE64.decode(&tags["b"]).map_err(|e| e.to_string())?;
+        let signature = Signature::from_slice(&signature_bytes).map_err(|e| e.to_string())?;
+        // RFC 8463: Ed25519 signs the SHA-256 hash of the signed data
+        public_key
+            .verify_strict(&Sha256::digest(signed), &signature)
+            .map_err(|e| e.to_string())
+    }
+
+    async fn send_signed_email() -> String {
+        let dkim = signing_config(
+            "mail",
+            "tld.com",
+            &BASE64.encode(SECRET),
+            DkimSigningAlgorithm::Ed25519,
+        )
+        .unwrap();
+        let stub = Arc::new(AsyncStubTransport::new_ok());
+        let client = EmailClient::new(stub.clone(), "blog@tld.com")
+            .unwrap()
+            .with_dkim(dkim);
+
+        let recipient = SubscriberEmail::parse("reader@tld.com".into()).unwrap();
+        client
+            .send_bulk_email(
+                &recipient,
+                "Hello",
+                "<p>Hi   there</p>",
+                "Hi there",
+                "https://tld.com/unsubscribe",
+                "token",
+            )
+            .await
+            .unwrap();
+        stub.messages().await.remove(0).1
+    }
+
+    #[tokio::test]
+    async fn signature_verifies_against_public_key() {
+        let message = send_signed_email().await;
+        let public_key = SigningKey::from_bytes(&SECRET).verifying_key();
+        assert_eq!(verify(&message, &public_key), Ok(()));
+        assert!(message.contains("d=tld.com"));
+        assert!(message.contains("s=mail"));
+    }
+
+    #[tokio::test]
+    async fn signature_does_not_verify_against_other_key() {
+        let message = send_signed_email().await;
+        let other_key = SigningKey::from_bytes(&[8; 32]).verifying_key();
+        assert_err!(verify(&message, &other_key));
+    }
+
+    #[tokio::test]
+    async fn tampered_message_does_not_verify() {
+        let message = send_signed_email().await.replace("Hello", "Goodbye");
+        let public_key = SigningKey::from_bytes(&SECRET).verifying_key();
+        assert_err!(verify(&message, &public_key));
+    }
+
+    #[test]
+    fn invalid_private_key_is_rejected() {
+        assert_err!(signing_config(
+            "mail",
+            "tld.com",
+            "not a key",
+            DkimSigningAlgorithm::Rsa
+        ));
+        assert_err!(signing_config(
+            "mail",
+            "tld.com",
+            &BASE64.encode([1; 16]),
+            DkimSigningAlgorithm::Ed25519
+        ));
+    }
+}
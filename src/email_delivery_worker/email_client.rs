@@ -1,15 +1,22 @@
+use crate::bounces::{unsubscribe_request_subject, verp_return_path};
 use crate::domain::SubscriberEmail;
 use crate::util::error_chain_fmt;
+use lettre::address::{Address, Envelope};
+use lettre::message::dkim::DkimConfig;
+use lettre::message::header::{Header, HeaderName, HeaderValue};
 use lettre::message::Mailbox;
 use lettre::message::{Message, MultiPart};
 use lettre::AsyncTransport;
 use std::error::Error as StdError;
 use std::sync::Arc;
 use tracing_log::log;
+use uuid::Uuid;
 
 pub struct EmailClient<T: AsyncTransport + Send + Sync> {
     smtp_client: Arc<T>,
     sender: Mailbox,
+    dkim: Option<DkimConfig>,
+    bounce_address: Option<Address>,
 }
 
 // TODO: nice recursive Debug trait like in routes/subscriptions.rs
@@ -56,6 +63,66 @@ impl std::error::Error for EmailClientError {
     }
 }
 
+/// `List-Unsubscribe` header (RFC 2369) offering an HTTPS link, which supports
+/// one-click unsubscribing, and a `mailto:` fallback.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListUnsubscribe {
+    pub url: String,
+    pub mailto: String,
+}
+
+impl Header for ListUnsubscribe {
+    fn name() -> HeaderName {
+        HeaderName::new_from_ascii_str("List-Unsubscribe")
+    }
+
+    fn parse(s: &str) -> Result<Self, Box<dyn StdError + Send + Sync>> {
+        let uris: Vec<_> = s
+            .split(',')
+            .map(|uri| uri.trim().trim_start_matches('<').trim_end_matches('>'))
+            .collect();
+        match uris.as_slice() {
+            [url, mailto] => Ok(Self {
+                url: url.to_string(),
+                mailto: mailto
+                    .strip_prefix("mailto:")
+                    .ok_or("Expected a mailto: URI")?
+                    .to_string(),
+            }),
+            _ => Err("Expected an HTTPS and a mailto: URI".into()),
+        }
+    }
+
+    fn display(&self) -> HeaderValue {
+        HeaderValue::new(
+            Self::name(),
+            format!("<{}>, <mailto:{}>", self.url, self.mailto),
+        )
+    }
+}
+
+/// `List-Unsubscribe-Post` header (RFC 8058), telling mailbox providers they
+/// may unsubscribe by POSTing to the `List-Unsubscribe` URL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListUnsubscribePost;
+
+impl Header for ListUnsubscribePost {
+    fn name() -> HeaderName {
+        HeaderName::new_from_ascii_str("List-Unsubscribe-Post")
+    }
+
+    fn parse(s: &str) -> Result<Self, Box<dyn StdError + Send + Sync>> {
+        match s.trim() {
+            "List-Unsubscribe=One-Click" => Ok(Self),
+            _ => Err("Expected List-Unsubscribe=One-Click".into()),
+        }
+    }
+
+    fn display(&self) -> HeaderValue {
+        HeaderValue::new(Self::name(), "List-Unsubscribe=One-Click".into())
+    }
+}
+
 impl<T> EmailClient<T>
 where
     T: AsyncTransport + Send + Sync,
@@ -69,30 +136,101 @@ where
         Ok(EmailClient {
             smtp_client,
             sender,
+            dkim: None,
+            bounce_address: None,
         })
     }
 
+    /// Send from a VERP return path based on `bounce_address`, so that bounces
+    /// are delivered there and identify the recipient they are about.
+    pub fn with_bounce_address(mut self, bounce_address: Address) -> Self {
+        self.bounce_address = Some(bounce_address);
+        self
+    }
+
+    /// DKIM-sign every message before it is handed to the transport.
+    pub fn with_dkim(mut self, dkim: DkimConfig) -> Self {
+        self.dkim = Some(dkim);
+        self
+    }
+
+    /// Send an email, returning its Message-ID.
     pub async fn send_email(
         &self,
         recipient: &SubscriberEmail,
         subject: &str,
         html_content: &str,
         text_content: &str,
-    ) -> Result<(), EmailClientError> {
+    ) -> Result<String, EmailClientError> {
+        self.send(recipient, subject, html_content, text_content, None)
+            .await
+    }
+
+    /// Send mailing list email, with headers letting the recipient unsubscribe
+    /// from their mail client. `unsubscribe_url` must accept RFC 8058 one-click
+    /// POST requests, and `unsubscribe_token` is the subscriber's unsubscribe
+    /// token, for requests sent by email instead.
+    pub async fn send_bulk_email(
+        &self,
+        recipient: &SubscriberEmail,
+        subject: &str,
+        html_content: &str,
+        text_content: &str,
+        unsubscribe_url: &str,
+        unsubscribe_token: &str,
+    ) -> Result<String, EmailClientError> {
+        // Requests sent to the bounce address are acted on by process-bounces
+        let mailbox = self.bounce_address.as_ref().unwrap_or(&self.sender.email);
+        let request_subject = unsubscribe_request_subject(unsubscribe_token).replace(' ', "%20");
+        let list_unsubscribe = ListUnsubscribe {
+            url: unsubscribe_url.into(),
+            mailto: format!("{}?subject={}", mailbox, request_subject),
+        };
+        self.send(
+            recipient,
+            subject,
+            html_content,
+            text_content,
+            Some(list_unsubscribe),
+        )
+        .await
+    }
+
+    async fn send(
+        &self,
+        recipient: &SubscriberEmail,
+        subject: &str,
+        html_content: &str,
+        text_content: &str,
+        list_unsubscribe: Option<ListUnsubscribe>,
+    ) -> Result<String, EmailClientError> {
         let mailbox: Mailbox = recipient.as_ref().parse()?;
+        let message_id = format!("{}@{}", Uuid::new_v4(), self.sender.email.domain());
 
-        let message = Message::builder()
+        let mut builder = Message::builder()
             .from(self.sender.clone())
-            .to(mailbox)
+            .to(mailbox.clone())
             .subject(subject)
-            .multipart(MultiPart::alternative_plain_html(
-                String::from(text_content),
-                String::from(html_content),
-            ))?;
+            .message_id(Some(format!("<{}>", message_id)));
+        if let Some(bounce_address) = &self.bounce_address {
+            let return_path = verp_return_path(bounce_address, recipient.as_ref())?;
+            builder = builder.envelope(Envelope::new(Some(return_path), vec![mailbox.email])?);
+        }
+        if let Some(list_unsubscribe) = list_unsubscribe {
+            builder = builder.header(list_unsubscribe).header(ListUnsubscribePost);
+        }
+
+        let mut message = builder.multipart(MultiPart::alternative_plain_html(
+            String::from(text_content),
+            String::from(html_content),
+        ))?;
+        if let Some(dkim) = &self.dkim {
+            message.sign(dkim);
+        }
 
         log::info!("About to send email...");
         match self.smtp_client.send(message).await {
-            Ok(_) => Ok(()),
+            Ok(_) => Ok(message_id),
             // TODO: This is  terrible error handling fix it up
             Err(e) => {
                 log::error!("Email error: {:?}", e);
@@ -109,12 +247,13 @@ where
 
 #[cfg(test)]
 mod tests {
-    use super::EmailClient;
+    use super::{EmailClient, ListUnsubscribe};
     use crate::domain::SubscriberEmail;
     use claims::{assert_err, assert_ok};
     use fake::faker::internet::en::SafeEmail;
     use fake::faker::lorem::en::{Paragraph, Sentence};
     use fake::Fake;
+    use lettre::message::header::Header;
     use lettre::transport::stub::AsyncStubTransport;
     use std::sync::Arc;
 
@@ -149,4 +288,127 @@ mod tests {
                 .await
         );
     }
+
+    #[tokio::test]
+    async fn bulk_email_has_one_click_unsubscribe_headers() {
+        let stub_client = Arc::new(AsyncStubTransport::new_ok());
+        let client = EmailClient::new(stub_client.clone(), "test@tld.com").unwrap();
+
+        let recipient = SubscriberEmail::parse(SafeEmail().fake()).unwrap();
+        assert_ok!(
+            client
+                .send_bulk_email(
+                    &recipient,
+                    "Subject",
+                    "<p>Hi</p>",
+                    "Hi",
+                    "https://tld.com/unsubscribe",
+                    "token"
+                )
+                .await
+        );
+
+        let (_, message) = &stub_client.messages().await[0];
+        // Unfold long headers
+        let message = message.replace("\r\n ", " ").replace("\n ", " ");
+        assert!(message.contains(
+            "List-Unsubscribe: <https://tld.com/unsubscribe>, \
+             <mailto:test@tld.com?subject=unsubscribe%20token>"
+        ));
+        assert!(message.contains("List-Unsubscribe-Post: List-Unsubscribe=One-Click"));
+    }
+
+    #[tokio::test]
+    async fn mailto_unsubscribe_goes_to_bounce_address() {
+        let stub_client = Arc::new(AsyncStubTransport::new_ok());
+        let client = EmailClient::new(stub_client.clone(), "test@tld.com")
+            .unwrap()
+            .with_bounce_address("bounces@tld.com".parse().unwrap());
+
+        let recipient = SubscriberEmail::parse(SafeEmail().fake()).unwrap();
+        assert_ok!(
+            client
+                .send_bulk_email(
+                    &recipient,
+                    "Subject",
+                    "<p>Hi</p>",
+                    "Hi",
+                    "https://tld.com/unsubscribe",
+                    "token"
+                )
+                .await
+        );
+
+        let (_, message) = &stub_client.messages().await[0];
+        let message = message.replace("\r\n ", " ").replace("\n ", " ");
+        assert!(message.contains("<mailto:bounces@tld.com?subject=unsubscribe%20token>"));
+    }
+
+    #[tokio::test]
+    async fn message_id_is_returned() {
+        let stub_client = Arc::new(AsyncStubTransport::new_ok());
+        let client = EmailClient::new(stub_client.clone(), "test@tld.com").unwrap();
+
+        let recipient = SubscriberEmail::parse(SafeEmail().fake()).unwrap();
+        let message_id = client
+            .send_email(&recipient, "Subject", "<p>Hi</p>", "Hi")
+            .await
+            .unwrap();
+
+        assert!(message_id.ends_with("@tld.com"));
+        let (_, message) = &stub_client.messages().await[0];
+        assert!(message.contains(&format!("Message-ID: <{}>", message_id)));
+    }
+
+    #[tokio::test]
+    async fn bounce_address_sets_verp_return_path() {
+        let stub_client = Arc::new(AsyncStubTransport::new_ok());
+        let client = EmailClient::new(stub_client.clone(), "test@tld.com")
+            .unwrap()
+            .with_bounce_address("bounces@tld.com".parse().unwrap());
+
+        let recipient = SubscriberEmail::parse("reader@other.com".into()).unwrap();
+        assert_ok!(
+            client
+                .send_email(&recipient, "Subject", "<p>Hi</p>", "Hi")
+                .await
+        );
+
+        let (envelope, _) = &stub_client.messages().await[0];
+        assert_eq!(
+            envelope.from().unwrap().to_string(),
+            "bounces+reader=other.com@tld.com"
+        );
+    }
+
+    #[tokio::test]
+    async fn transactional_email_has_no_unsubscribe_headers() {
+        let stub_client = Arc::new(AsyncStubTransport::new_ok());
+        let client = EmailClient::new(stub_client.clone(), "test@tld.com").unwrap();
+
+        let recipient = SubscriberEmail::parse(SafeEmail().fake()).unwrap();
+        assert_ok!(
+            client
+                .send_email(&recipient, "Subject", "<p>Hi</p>", "Hi")
+                .await
+        );
+
+        let (_, message) = &stub_client.messages().await[0];
+        assert!(!message.contains("List-Unsubscribe"));
+    }
+
+    #[test]
+    fn list_unsubscribe_header_can_be_parsed() {
+        let header = ListUnsubscribe::parse(
+            "<https://tld.com/unsubscribe?id=1>, <mailto:blog@tld.com?subject=unsubscribe>",
+        )
+        .unwrap();
+        assert_eq!(
+            header,
+            ListUnsubscribe {
+                url: "https://tld.com/unsubscribe?id=1".into(),
+                mailto: "blog@tld.com?subject=unsubscribe".into(),
+            }
+        );
+    }
 }
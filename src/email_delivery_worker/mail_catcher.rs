@@ -0,0 +1,137 @@
+use chrono::{DateTime, Utc};
+use lettre::address::Envelope;
+use mail_parser::MessageParser;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+/// Oldest messages are dropped once the catcher holds this many.
+const MAX_CAPTURED_EMAILS: usize = 200;
+
+/// An email captured by the `MailCatcher` transport.
+#[derive(Debug, Clone)]
+pub struct CapturedEmail {
+    pub id: usize,
+    pub received_at: DateTime<Utc>,
+    pub to: Vec<String>,
+    raw: Vec<u8>,
+}
+
+/// The parts of a captured email shown in the dev inbox.
+#[derive(Debug, Serialize)]
+pub struct CapturedEmailView {
+    pub id: usize,
+    pub received_at: String,
+    pub to: Vec<String>,
+    pub subject: String,
+    pub html: Option<String>,
+    pub text: Option<String>,
+}
+
+impl CapturedEmail {
+    pub fn view(&self) -> CapturedEmailView {
+        let parsed = MessageParser::default().parse(&self.raw);
+        let subject = parsed
+            .as_ref()
+            .and_then(|m| m.subject())
+            .unwrap_or("(no subject)");
+        let html = parsed.as_ref().and_then(|m| m.body_html(0));
+        let text = parsed.as_ref().and_then(|m| m.body_text(0));
+        CapturedEmailView {
+            id: self.id,
+            received_at: self.received_at.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+            to: self.to.clone(),
+            subject: subject.to_string(),
+            html: html.map(|s| s.into_owned()),
+            text: text.map(|s| s.into_owned()),
+        }
+    }
+}
+
+/// Development transport which keeps outgoing email in memory instead of
+/// sending it, so it can be read at `/dev/mail`.
+#[derive(Debug, Clone, Default)]
+pub struct MailCatcher {
+    inner: Arc<Mutex<MailCatcherInner>>,
+}
+
+#[derive(Debug, Default)]
+struct MailCatcherInner {
+    next_id: usize,
+    emails: VecDeque<CapturedEmail>,
+}
+
+impl MailCatcher {
+    pub fn capture(&self, envelope: &Envelope, raw: &[u8]) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.next_id += 1;
+        let email = CapturedEmail {
+            id: inner.next_id,
+            received_at: Utc::now(),
+            to: envelope.to().iter().map(|a| a.to_string()).collect(),
+            raw: raw.to_vec(),
+        };
+        inner.emails.push_front(email);
+        inner.emails.truncate(MAX_CAPTURED_EMAILS);
+    }
+
+    /// Captured emails, most recent first.
+    pub fn emails(&self) -> Vec<CapturedEmail> {
+        self.inner.lock().unwrap().emails.iter().cloned().collect()
+    }
+
+    pub fn email(&self, id: usize) -> Option<CapturedEmail> {
+        let inner = self.inner.lock().unwrap();
+        inner.emails.iter().find(|e| e.id == id).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MailCatcher;
+    use crate::domain::SubscriberEmail;
+    use crate::email_delivery_worker::{EmailClient, EmailTransport};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn captured_email_can_be_read_back() {
+        let catcher = MailCatcher::default();
+        let transport = EmailTransport::Catcher(catcher.clone());
+        let client = EmailClient::new(Arc::new(transport), "blog@tld.com").unwrap();
+
+        let recipient = SubscriberEmail::parse("reader@tld.com".into()).unwrap();
+        client
+            .send_email(&recipient, "Hello", "<p>Hi there</p>", "Hi there")
+            .await
+            .unwrap();
+
+        let emails = catcher.emails();
+        assert_eq!(emails.len(), 1);
+        let view = catcher.email(emails[0].id).unwrap().view();
+        assert_eq!(view.to, vec!["reader@tld.com"]);
+        assert_eq!(view.subject, "Hello");
+        assert!(view.html.unwrap().contains("<p>Hi there</p>"));
+        assert!(view.text.unwrap().contains("Hi there"));
+    }
+
+    #[tokio::test]
+    async fn most_recent_email_is_listed_first() {
+        let catcher = MailCatcher::default();
+        let client = EmailClient::new(
+            Arc::new(EmailTransport::Catcher(catcher.clone())),
+            "blog@tld.com",
+        )
+        .unwrap();
+
+        let recipient = SubscriberEmail::parse("reader@tld.com".into()).unwrap();
+        for subject in ["First", "Second"] {
+            client
+                .send_email(&recipient, subject, "<p>Hi</p>", "Hi")
+                .await
+                .unwrap();
+        }
+
+        let subjects: Vec<_> = catcher.emails().iter().map(|e| e.view().subject).collect();
+        assert_eq!(subjects, vec!["Second", "First"]);
+    }
+}
@@ -1,6 +1,15 @@
+mod dkim;
 mod email_client;
-mod email_template;
+mod mail_catcher;
+mod rate_limiter;
+mod retry_policy;
+mod transport;
 mod worker;
 
-pub use email_client::EmailClient;
+pub use dkim::DkimSettings;
+pub use email_client::{EmailClient, ListUnsubscribe, ListUnsubscribePost};
+pub use mail_catcher::{CapturedEmail, CapturedEmailView, MailCatcher};
+pub use rate_limiter::{RateLimiter, RateLimits};
+pub use retry_policy::{Jitter, RetryPolicies, RetryPolicy};
+pub use transport::{EmailBackend, EmailTransport, HttpApiTransport, SmtpTls};
 pub use worker::worker;
@@ -0,0 +1,185 @@
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use std::time::Duration;
+
+/// A sending budget: at most `capacity` emails per `window`, refilled continuously.
+#[derive(Debug, Clone, Copy)]
+struct Budget {
+    name: &'static str,
+    capacity: u32,
+    window: Duration,
+}
+
+impl Budget {
+    fn refill_rate_per_sec(&self) -> f64 {
+        self.capacity as f64 / self.window.as_secs_f64()
+    }
+
+    /// Number of tokens in a bucket holding `tokens` after `elapsed` has passed.
+    fn refill(&self, tokens: f64, elapsed: Duration) -> f64 {
+        (tokens + elapsed.as_secs_f64() * self.refill_rate_per_sec()).min(self.capacity as f64)
+    }
+
+    /// Time until a bucket holding `tokens` has a whole token available.
+    fn time_until_available(&self, tokens: f64) -> Duration {
+        if tokens >= 1.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64((1.0 - tokens) / self.refill_rate_per_sec())
+    }
+}
+
+/// Outbound sending limits, e.g. to stay within an SMTP provider's quotas.
+/// Limits which aren't set are not enforced.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimits {
+    budgets: Vec<Budget>,
+}
+
+impl RateLimits {
+    pub fn per_second(self, limit: Option<u32>) -> Self {
+        self.with_budget("per_second", limit, Duration::from_secs(1))
+    }
+
+    pub fn per_hour(self, limit: Option<u32>) -> Self {
+        self.with_budget("per_hour", limit, Duration::from_secs(60 * 60))
+    }
+
+    pub fn per_day(self, limit: Option<u32>) -> Self {
+        self.with_budget("per_day", limit, Duration::from_secs(24 * 60 * 60))
+    }
+
+    fn with_budget(mut self, name: &'static str, limit: Option<u32>, window: Duration) -> Self {
+        self.budgets.retain(|b| b.name != name);
+        if let Some(capacity) = limit.filter(|&n| n > 0) {
+            self.budgets.push(Budget {
+                name,
+                capacity,
+                window,
+            });
+        }
+        self
+    }
+}
+
+/// Token bucket rate limiter whose state lives in the `email_send_budgets` table,
+/// so that it is shared between workers and survives restarts.
+pub struct RateLimiter {
+    limits: RateLimits,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Self { limits }
+    }
+
+    /// Take a token from every budget. If any budget is exhausted nothing is taken
+    /// and the time to wait before trying again is returned instead.
+    #[tracing::instrument(skip_all)]
+    pub async fn try_acquire(
+        &self,
+        connection_pool: &PgPool,
+    ) -> Result<Option<Duration>, sqlx::Error> {
+        if self.limits.budgets.is_empty() {
+            return Ok(None);
+        }
+
+        let mut transaction = connection_pool.begin().await?;
+
+        let mut wait = Duration::ZERO;
+        let mut remaining = Vec::with_capacity(self.limits.budgets.len());
+        for budget in &self.limits.budgets {
+            // Buckets start out full
+            let bucket = sqlx::query!(
+                r#"
+                INSERT INTO email_send_budgets (name, tokens)
+                VALUES ($1, $2)
+                ON CONFLICT (name) DO UPDATE SET name = EXCLUDED.name
+                RETURNING tokens, refilled_at
+                "#,
+                budget.name,
+                budget.capacity as f64
+            )
+            .fetch_one(&mut *transaction)
+            .await?;
+
+            let tokens = budget.refill(bucket.tokens, elapsed_since(bucket.refilled_at));
+            wait = wait.max(budget.time_until_available(tokens));
+            remaining.push((budget.name, tokens - 1.0));
+        }
+
+        if !wait.is_zero() {
+            transaction.rollback().await?;
+            return Ok(Some(wait));
+        }
+
+        for (name, tokens) in remaining {
+            sqlx::query!(
+                r#"
+                UPDATE email_send_budgets
+                SET tokens = $1, refilled_at = NOW()
+                WHERE name = $2
+                "#,
+                tokens,
+                name
+            )
+            .execute(&mut *transaction)
+            .await?;
+        }
+        transaction.commit().await?;
+
+        Ok(None)
+    }
+}
+
+fn elapsed_since(t: DateTime<Utc>) -> Duration {
+    (Utc::now() - t).to_std().unwrap_or(Duration::ZERO)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Budget, RateLimits};
+    use std::time::Duration;
+
+    fn per_hour(capacity: u32) -> Budget {
+        Budget {
+            name: "per_hour",
+            capacity,
+            window: Duration::from_secs(3600),
+        }
+    }
+
+    #[test]
+    fn bucket_refills_in_proportion_to_elapsed_time() {
+        let budget = per_hour(60);
+        let tokens = budget.refill(0.0, Duration::from_secs(120));
+        assert!((tokens - 2.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn bucket_never_exceeds_capacity() {
+        let budget = per_hour(60);
+        assert_eq!(budget.refill(59.5, Duration::from_secs(3600 * 24)), 60.0);
+    }
+
+    #[test]
+    fn no_wait_when_a_token_is_available() {
+        assert_eq!(per_hour(60).time_until_available(1.0), Duration::ZERO);
+    }
+
+    #[test]
+    fn exhausted_bucket_waits_for_the_next_token() {
+        let wait = per_hour(60).time_until_available(0.5);
+        assert_eq!(wait.as_secs(), 30);
+    }
+
+    #[test]
+    fn unset_and_zero_limits_are_not_enforced() {
+        let limits = RateLimits::default()
+            .per_second(None)
+            .per_hour(Some(0))
+            .per_day(Some(500));
+        assert_eq!(limits.budgets.len(), 1);
+        assert_eq!(limits.budgets[0].name, "per_day");
+    }
+}
@@ -0,0 +1,248 @@
+use crate::email_delivery_queue::TaskPriority;
+use rand::Rng;
+use std::time::Duration;
+
+/// How randomness is mixed into retry delays so that failed tasks don't all
+/// retry in lockstep.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Jitter {
+    None,
+    /// Uniformly random between zero and the exponential delay
+    Full,
+    /// Uniformly random between the base interval and three times the previous
+    /// delay, as described in the AWS architecture blog's "Exponential Backoff
+    /// And Jitter"
+    Decorrelated,
+}
+
+/// Exponential backoff for failed email delivery tasks.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    base_interval: Duration,
+    multiplier: f64,
+    max_interval: Duration,
+    max_attempts: u32,
+    jitter: Jitter,
+}
+
+impl RetryPolicy {
+    pub fn new(base_interval: Duration, max_interval: Duration) -> Self {
+        Self {
+            base_interval,
+            multiplier: 2.0,
+            max_interval: max_interval.max(base_interval),
+            max_attempts: u32::MAX,
+            jitter: Jitter::None,
+        }
+    }
+
+    /// A policy which gives up on a task after its first failure.
+    pub fn no_retries() -> Self {
+        Self::new(Duration::ZERO, Duration::ZERO).max_attempts(1)
+    }
+
+    pub fn multiplier(mut self, multiplier: f64) -> Self {
+        self.multiplier = multiplier.max(1.0);
+        self
+    }
+
+    /// Total number of delivery attempts made before giving up on a task.
+    pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    pub fn jitter(mut self, jitter: Jitter) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Delay before retrying a task which has just failed for the
+    /// `n_retries + 1`th time, or `None` if the task should be given up on.
+    /// `previous_delay` is the delay applied before the failed attempt, if any.
+    pub fn next_delay<R: Rng>(
+        &self,
+        n_retries: u32,
+        previous_delay: Option<Duration>,
+        rng: &mut R,
+    ) -> Option<Duration> {
+        if n_retries.saturating_add(1) >= self.max_attempts {
+            return None;
+        }
+
+        let base = self.base_interval.as_secs_f64();
+        let max = self.max_interval.as_secs_f64();
+        // Saturates to infinity rather than overflowing for large retry counts
+        let exponential = (base * self.multiplier.powf(n_retries as f64)).min(max);
+
+        let (secs, min_delay) = match self.jitter {
+            Jitter::None => (exponential, Duration::ZERO),
+            Jitter::Full => (rng.gen_range(0.0..=exponential), Duration::ZERO),
+            Jitter::Decorrelated => {
+                let previous = previous_delay.map_or(base, |d| d.as_secs_f64());
+                let upper = (previous * 3.0).max(base);
+                (rng.gen_range(base..=upper).min(max), self.base_interval)
+            }
+        };
+
+        // Clamp again to guard against float rounding
+        Some(Duration::from_secs_f64(secs).clamp(min_delay, self.max_interval))
+    }
+}
+
+/// Retry policies for each kind of failure the delivery worker handles.
+#[derive(Debug, Clone)]
+pub struct RetryPolicies {
+    /// Failures to send transactional email
+    pub transactional: RetryPolicy,
+    /// Failures to send bulk email
+    pub bulk: RetryPolicy,
+    /// Tasks whose data can't be sent at all, e.g. an unparseable address
+    pub corrupted_data: RetryPolicy,
+}
+
+impl RetryPolicies {
+    pub fn for_priority(&self, priority: TaskPriority) -> &RetryPolicy {
+        match priority {
+            TaskPriority::Transactional => &self.transactional,
+            TaskPriority::Bulk => &self.bulk,
+        }
+    }
+}
+
+impl Default for RetryPolicies {
+    fn default() -> Self {
+        Self {
+            transactional: RetryPolicy::new(Duration::from_secs(30), Duration::from_secs(60 * 60))
+                .max_attempts(10)
+                .jitter(Jitter::Full),
+            bulk: RetryPolicy::new(Duration::from_secs(60), Duration::from_secs(6 * 60 * 60))
+                .max_attempts(8)
+                .jitter(Jitter::Decorrelated),
+            corrupted_data: RetryPolicy::no_retries(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Jitter, RetryPolicy};
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+    use std::time::Duration;
+
+    #[derive(Debug, Clone)]
+    struct RetryPolicyFixture(RetryPolicy);
+
+    impl quickcheck::Arbitrary for RetryPolicyFixture {
+        fn arbitrary<G: quickcheck::Gen>(g: &mut G) -> Self {
+            let base = Duration::from_millis(u64::from(u32::arbitrary(g)));
+            let max = base + Duration::from_millis(u64::from(u32::arbitrary(g)));
+            let jitter = match u8::arbitrary(g) % 3 {
+                0 => Jitter::None,
+                1 => Jitter::Full,
+                _ => Jitter::Decorrelated,
+            };
+            let policy = RetryPolicy::new(base, max)
+                .multiplier(1.0 + f64::from(u8::arbitrary(g)) / 32.0)
+                .max_attempts(u32::arbitrary(g))
+                .jitter(jitter);
+            Self(policy)
+        }
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn delay_never_exceeds_max_interval(
+        policy: RetryPolicyFixture,
+        n_retries: u32,
+        previous_ms: Option<u32>,
+        seed: u64,
+    ) -> bool {
+        let policy = policy.0;
+        let previous = previous_ms.map(|ms| Duration::from_millis(u64::from(ms)));
+        let mut rng = StdRng::seed_from_u64(seed);
+        policy
+            .next_delay(n_retries, previous, &mut rng)
+            .is_none_or(|delay| delay <= policy.max_interval)
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn gives_up_once_max_attempts_are_reached(policy: RetryPolicyFixture, n_retries: u32) -> bool {
+        let policy = policy.0;
+        let mut rng = StdRng::seed_from_u64(0);
+        let gave_up = policy.next_delay(n_retries, None, &mut rng).is_none();
+        gave_up == (n_retries.saturating_add(1) >= policy.max_attempts)
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn delay_without_jitter_never_decreases(policy: RetryPolicyFixture, n_retries: u16) -> bool {
+        let policy = policy.0.jitter(Jitter::None).max_attempts(u32::MAX);
+        let n_retries = u32::from(n_retries);
+        let mut rng = StdRng::seed_from_u64(0);
+        let delay = policy.next_delay(n_retries, None, &mut rng);
+        let next_delay = policy.next_delay(n_retries + 1, None, &mut rng);
+        delay <= next_delay
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn full_jitter_is_at_most_the_exponential_delay(
+        policy: RetryPolicyFixture,
+        n_retries: u16,
+        seed: u64,
+    ) -> bool {
+        let policy = policy.0.max_attempts(u32::MAX);
+        let n_retries = u32::from(n_retries);
+        let mut rng = StdRng::seed_from_u64(seed);
+        let jittered = policy
+            .clone()
+            .jitter(Jitter::Full)
+            .next_delay(n_retries, None, &mut rng);
+        let exponential = policy
+            .jitter(Jitter::None)
+            .next_delay(n_retries, None, &mut rng);
+        jittered <= exponential
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn decorrelated_jitter_is_at_least_the_base_interval(
+        policy: RetryPolicyFixture,
+        previous_ms: Option<u32>,
+        seed: u64,
+    ) -> bool {
+        let policy = policy.0.jitter(Jitter::Decorrelated).max_attempts(u32::MAX);
+        let previous = previous_ms.map(|ms| Duration::from_millis(u64::from(ms)));
+        let mut rng = StdRng::seed_from_u64(seed);
+        policy
+            .next_delay(0, previous, &mut rng)
+            .is_some_and(|delay| delay >= policy.base_interval)
+    }
+
+    #[test]
+    fn backoff_doubles_by_default() {
+        let policy = RetryPolicy::new(Duration::from_secs(60), Duration::from_secs(3600));
+        let mut rng = StdRng::seed_from_u64(0);
+        let delays: Vec<_> = (0..4)
+            .map(|n| policy.next_delay(n, None, &mut rng).unwrap().as_secs())
+            .collect();
+        assert_eq!(delays, vec![60, 120, 240, 480]);
+    }
+
+    #[test]
+    fn huge_retry_counts_are_capped_rather_than_overflowing() {
+        let policy = RetryPolicy::new(Duration::from_secs(60), Duration::from_secs(3600));
+        let mut rng = StdRng::seed_from_u64(0);
+        assert_eq!(
+            policy.next_delay(100_000, None, &mut rng),
+            Some(Duration::from_secs(3600))
+        );
+    }
+
+    #[test]
+    fn no_retries_policy_gives_up_immediately() {
+        let mut rng = StdRng::seed_from_u64(0);
+        assert_eq!(
+            RetryPolicy::no_retries().next_delay(0, None, &mut rng),
+            None
+        );
+    }
+}
@@ -0,0 +1,326 @@
+use crate::email_delivery_worker::mail_catcher::MailCatcher;
+use crate::util::{read_env_or_panic, read_optional_env};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use lettre::address::Envelope;
+use lettre::transport::file::AsyncFileTransport;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::transport::smtp::{AsyncSmtpTransport, PoolConfig};
+use lettre::{AsyncTransport, Tokio1Executor};
+use secrecy::{ExposeSecret, Secret};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// How the SMTP connection is secured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmtpTls {
+    /// Plaintext, only suitable for a relay on the local machine
+    None,
+    /// Upgrade a plaintext connection with STARTTLS, usually on port 587
+    StartTls,
+    /// Implicit TLS, usually on port 465
+    Tls,
+}
+
+impl FromStr for SmtpTls {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "starttls" => Ok(Self::StartTls),
+            "tls" => Ok(Self::Tls),
+            other => Err(format!("Unknown SMTP TLS mode {}", other)),
+        }
+    }
+}
+
+/// Where outgoing email is sent, chosen by the `EMAIL_BACKEND` env var.
+pub enum EmailBackend {
+    Smtp {
+        host: String,
+        port: u16,
+        tls: SmtpTls,
+        credentials: Option<(String, Secret<String>)>,
+    },
+    /// Write each email to an `.eml` file in `dir`, for local development
+    File { dir: PathBuf },
+    /// POST each email to a Postmark/SES-style JSON API
+    HttpApi { url: String, token: Secret<String> },
+    /// Keep email in memory to be read at `/dev/mail`. Only available in dev mode.
+    Catcher,
+}
+
+impl EmailBackend {
+    /// Reads the backend configuration from the environment. The defaults relay
+    /// through Gmail using the blog's own address.
+    pub fn from_env() -> Self {
+        let backend = std::env::var("EMAIL_BACKEND").unwrap_or_else(|_| "smtp".into());
+        match backend.as_str() {
+            "smtp" => {
+                let username = std::env::var("SMTP_USERNAME")
+                    .or_else(|_| std::env::var("BLOG_EMAIL_ADDRESS"))
+                    .ok();
+                let password = std::env::var("SMTP_PASSWORD")
+                    .or_else(|_| std::env::var("BLOG_EMAIL_PASSWORD"))
+                    .ok();
+                Self::Smtp {
+                    host: std::env::var("SMTP_HOST").unwrap_or_else(|_| "smtp.gmail.com".into()),
+                    port: read_optional_env("SMTP_PORT").unwrap_or(587),
+                    tls: read_optional_env("SMTP_TLS").unwrap_or(SmtpTls::StartTls),
+                    credentials: username.zip(password.map(Secret::new)),
+                }
+            }
+            "file" => Self::File {
+                dir: std::env::var("EMAIL_FILE_DIR")
+                    .unwrap_or_else(|_| "mail".into())
+                    .into(),
+            },
+            "http" => Self::HttpApi {
+                url: read_env_or_panic("EMAIL_API_URL"),
+                token: Secret::new(read_env_or_panic("EMAIL_API_TOKEN")),
+            },
+            "catcher" => {
+                if !read_optional_env("APP_DEV_MODE").unwrap_or(false) {
+                    panic!("EMAIL_BACKEND=catcher requires APP_DEV_MODE=true");
+                }
+                Self::Catcher
+            }
+            other => panic!("Unknown EMAIL_BACKEND {}", other),
+        }
+    }
+
+    pub fn build(self) -> Result<EmailTransport, anyhow::Error> {
+        let transport = match self {
+            Self::Smtp {
+                host,
+                port,
+                tls,
+                credentials,
+            } => {
+                let builder = match tls {
+                    SmtpTls::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&host),
+                    SmtpTls::StartTls => {
+                        AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&host)?
+                    }
+                    SmtpTls::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&host)?,
+                };
+                let mut builder = builder
+                    .port(port)
+                    .timeout(Some(Duration::from_secs(10)))
+                    .pool_config(PoolConfig::new().max_size(20));
+                if let Some((username, password)) = credentials {
+                    builder = builder
+                        .credentials(Credentials::new(username, password.expose_secret().into()));
+                }
+                EmailTransport::Smtp(builder.build())
+            }
+            Self::File { dir } => {
+                std::fs::create_dir_all(&dir)?;
+                EmailTransport::File(AsyncFileTransport::new(dir))
+            }
+            Self::HttpApi { url, token } => {
+                EmailTransport::HttpApi(HttpApiTransport::new(url, token))
+            }
+            Self::Catcher => EmailTransport::Catcher(MailCatcher::default()),
+        };
+        Ok(transport)
+    }
+}
+
+/// The transport the email delivery worker sends through, selected by an
+/// `EmailBackend`.
+pub enum EmailTransport {
+    Smtp(AsyncSmtpTransport<Tokio1Executor>),
+    File(AsyncFileTransport<Tokio1Executor>),
+    HttpApi(HttpApiTransport),
+    Catcher(MailCatcher),
+}
+
+impl EmailTransport {
+    /// The captured email store, if this is the development mail catcher.
+    pub fn mail_catcher(&self) -> Option<&MailCatcher> {
+        match self {
+            Self::Catcher(catcher) => Some(catcher),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum EmailTransportError {
+    #[error("SMTP transport error")]
+    Smtp(#[from] lettre::transport::smtp::Error),
+    #[error("File transport error")]
+    File(#[from] lettre::transport::file::Error),
+    #[error("HTTP API transport error")]
+    HttpApi(#[from] HttpApiError),
+}
+
+#[async_trait]
+impl AsyncTransport for EmailTransport {
+    type Ok = ();
+    type Error = EmailTransportError;
+
+    async fn send_raw(&self, envelope: &Envelope, email: &[u8]) -> Result<(), Self::Error> {
+        match self {
+            Self::Smtp(t) => {
+                t.send_raw(envelope, email).await?;
+            }
+            Self::File(t) => {
+                t.send_raw(envelope, email).await?;
+            }
+            Self::HttpApi(t) => t.send_raw(envelope, email).await?,
+            Self::Catcher(catcher) => catcher.capture(envelope, email),
+        };
+        Ok(())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum HttpApiError {
+    #[error("Failed to send request to the email API")]
+    Request(#[from] reqwest::Error),
+    #[error("Email API responded with {status}: {body}")]
+    Rejected {
+        status: reqwest::StatusCode,
+        body: String,
+    },
+}
+
+/// Request body for sending a pre-formatted message, in the style of SES's raw
+/// email API. Sending the raw message rather than its parts preserves headers
+/// set by `EmailClient`.
+#[derive(serde::Serialize)]
+struct SendRawEmailRequest {
+    from: Option<String>,
+    to: Vec<String>,
+    raw_message: String,
+}
+
+/// Sends email by POSTing it to an HTTP JSON API, authenticated with a bearer
+/// token.
+pub struct HttpApiTransport {
+    client: reqwest::Client,
+    url: String,
+    token: Secret<String>,
+}
+
+impl HttpApiTransport {
+    pub fn new(url: String, token: Secret<String>) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .expect("Failed to build HTTP client");
+        Self { client, url, token }
+    }
+
+    async fn send_raw(&self, envelope: &Envelope, email: &[u8]) -> Result<(), HttpApiError> {
+        let body = SendRawEmailRequest {
+            from: envelope.from().map(|a| a.to_string()),
+            to: envelope.to().iter().map(|a| a.to_string()).collect(),
+            raw_message: BASE64.encode(email),
+        };
+
+        let response = self
+            .client
+            .post(&self.url)
+            .bearer_auth(self.token.expose_secret())
+            .json(&body)
+            .send()
+            .await?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(HttpApiError::Rejected { status, body });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EmailBackend, EmailTransport, HttpApiTransport};
+    use crate::domain::SubscriberEmail;
+    use crate::email_delivery_worker::EmailClient;
+    use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+    use claims::{assert_err, assert_ok};
+    use secrecy::Secret;
+    use std::sync::Arc;
+    use wiremock::matchers::{header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn http_client(server: &MockServer) -> EmailClient<EmailTransport> {
+        let transport = EmailTransport::HttpApi(HttpApiTransport::new(
+            format!("{}/email", server.uri()),
+            Secret::new("api-token".into()),
+        ));
+        EmailClient::new(Arc::new(transport), "blog@tld.com").unwrap()
+    }
+
+    #[tokio::test]
+    async fn http_api_receives_raw_message() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/email"))
+            .and(header("Authorization", "Bearer api-token"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let recipient = SubscriberEmail::parse("reader@tld.com".into()).unwrap();
+        assert_ok!(
+            http_client(&server)
+                .send_email(&recipient, "Hello", "<p>Hi</p>", "Hi")
+                .await
+        );
+
+        let requests = server.received_requests().await.unwrap();
+        let body: serde_json::Value = requests[0].body_json().unwrap();
+        assert_eq!(body["from"], "blog@tld.com");
+        assert_eq!(body["to"], serde_json::json!(["reader@tld.com"]));
+        let raw = BASE64
+            .decode(body["raw_message"].as_str().unwrap())
+            .unwrap();
+        assert!(String::from_utf8(raw).unwrap().contains("Subject: Hello"));
+    }
+
+    #[tokio::test]
+    async fn err_if_http_api_rejects_message() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(422))
+            .mount(&server)
+            .await;
+
+        let recipient = SubscriberEmail::parse("reader@tld.com".into()).unwrap();
+        assert_err!(
+            http_client(&server)
+                .send_email(&recipient, "Hello", "<p>Hi</p>", "Hi")
+                .await
+        );
+    }
+
+    #[tokio::test]
+    async fn file_backend_writes_eml_files() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let transport = EmailBackend::File { dir: dir.clone() }.build().unwrap();
+        let client = EmailClient::new(Arc::new(transport), "blog@tld.com").unwrap();
+
+        let recipient = SubscriberEmail::parse("reader@tld.com".into()).unwrap();
+        assert_ok!(
+            client
+                .send_email(&recipient, "Hello", "<p>Hi</p>", "Hi")
+                .await
+        );
+
+        let files: Vec<_> = std::fs::read_dir(&dir).unwrap().collect();
+        assert_eq!(files.len(), 1);
+        let path = files[0].as_ref().unwrap().path();
+        assert_eq!(path.extension().unwrap(), "eml");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
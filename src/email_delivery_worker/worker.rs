@@ -1,44 +1,165 @@
 use crate::{
     domain::{InvalidEmailError, SubscriberEmail},
-    email_delivery_queue,
+    email_delivery_queue::{self, TaskPriority},
     email_delivery_worker::{
         email_client::{EmailClient, EmailClientError},
-        email_template,
+        rate_limiter::RateLimiter,
+        retry_policy::{RetryPolicies, RetryPolicy},
     },
+    email_templates::{
+        one_click_unsubscribe_link, unsubscribe_token, EmailContent, EmailTemplates,
+        SubscriberLinks,
+    },
+    signed_token::TokenSigner,
 };
 use lettre::AsyncTransport;
-use sqlx::PgPool;
+use sqlx::{postgres::PgListener, PgPool, Postgres, Transaction};
 use std::sync::Arc;
 use std::time::Duration;
 use tracing_log::log;
+use uuid::Uuid;
 
-pub async fn worker<T>(email_client: Arc<EmailClient<T>>, connection_pool: Arc<PgPool>)
-where
+/// How long an idle worker waits for a notification before checking the queue
+/// anyway. New tasks wake the worker immediately, but tasks whose `send_after`
+/// lies in the future (e.g. retries being backed off) are only found by polling.
+const FALLBACK_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Maximum number of transactional emails sent in a row while bulk email is
+/// waiting, i.e. bulk email gets at least one in every `N + 1` sends.
+const MAX_TRANSACTIONAL_STREAK: u32 = 4;
+
+/// Decides which delivery lane the worker serves next. Transactional email is
+/// preferred, but bulk email is never starved by a steady stream of sign-ups.
+#[derive(Debug, Default)]
+struct LaneScheduler {
+    transactional_streak: u32,
+}
+
+impl LaneScheduler {
+    fn preferred(&self) -> TaskPriority {
+        if self.transactional_streak >= MAX_TRANSACTIONAL_STREAK {
+            TaskPriority::Bulk
+        } else {
+            TaskPriority::Transactional
+        }
+    }
+
+    fn record_sent(&mut self, priority: TaskPriority) {
+        match priority {
+            TaskPriority::Transactional => self.transactional_streak += 1,
+            TaskPriority::Bulk => self.transactional_streak = 0,
+        }
+    }
+}
+
+pub async fn worker<T>(
+    email_client: Arc<EmailClient<T>>,
+    email_templates: Arc<EmailTemplates>,
+    token_signer: Arc<TokenSigner>,
+    connection_pool: Arc<PgPool>,
+    rate_limiter: Arc<RateLimiter>,
+    retry_policies: Arc<RetryPolicies>,
+) where
     T: AsyncTransport + Sync + Send,
     T::Error: std::error::Error,
 {
+    let mut listener = listen_for_tasks(&connection_pool).await;
+    let mut scheduler = LaneScheduler::default();
+
     loop {
-        if let Err(e) = try_execute_task(&email_client, &connection_pool).await {
-            match e {
+        let result = try_execute_task(
+            &email_client,
+            &email_templates,
+            &token_signer,
+            &connection_pool,
+            &rate_limiter,
+            &retry_policies,
+            scheduler.preferred(),
+        )
+        .await;
+
+        match result {
+            Ok(priority) => scheduler.record_sent(priority),
+            Err(e) => match e {
                 TryTaskError::CorruptedData(_) => {
                     log::error!("Error in email delivery worker: {}", e);
                     continue;
                 }
+                TryTaskError::RecipientSuppressed(_) => {
+                    log::info!("{}, task dead-lettered", e);
+                    continue;
+                }
                 // Wait for tasks to become available
                 TryTaskError::NoPendingTask => {
                     log::debug!("No pending tasks. Worker sleeping..");
-                    tokio::time::sleep(Duration::from_secs(1)).await
+                    wait_for_task(&mut listener).await
+                }
+
+                // Pause delivery until the sending budget allows another email
+                TryTaskError::RateLimited(wait) => {
+                    log::info!("Sending budget exhausted. Pausing delivery for {:?}", wait);
+                    tokio::time::sleep(wait).await
                 }
 
                 // Sleep through (hopefully transient) db or email client errors.
                 // Would be nice to implement exponential backoff, alerting, and
                 // distinguish transient / fatal errors eventually
-                TryTaskError::DatabaseError(_) | TryTaskError::EmailClientError(_) => {
+                TryTaskError::DatabaseError(_)
+                | TryTaskError::EmailClientError(_)
+                | TryTaskError::TemplateError(_) => {
                     log::error!("Error in email delivery worker: {}", e);
                     tokio::time::sleep(Duration::from_secs(10)).await
                 }
-            }
+            },
+        }
+    }
+}
+
+/// Subscribe to queue notifications. If this fails the worker falls back to
+/// polling the queue every `FALLBACK_POLL_INTERVAL`.
+async fn listen_for_tasks(connection_pool: &PgPool) -> Option<PgListener> {
+    let mut listener = match PgListener::connect_with(connection_pool).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            log::error!(
+                "Failed to connect queue listener, falling back to polling: {}",
+                e
+            );
+            return None;
+        }
+    };
+    match listener
+        .listen(email_delivery_queue::EMAIL_QUEUE_CHANNEL)
+        .await
+    {
+        Ok(()) => Some(listener),
+        Err(e) => {
+            log::error!(
+                "Failed to listen for queue notifications, falling back to polling: {}",
+                e
+            );
+            None
+        }
+    }
+}
+
+async fn wait_for_task(listener: &mut Option<PgListener>) {
+    let Some(listener) = listener else {
+        tokio::time::sleep(FALLBACK_POLL_INTERVAL).await;
+        return;
+    };
+
+    match tokio::time::timeout(FALLBACK_POLL_INTERVAL, listener.recv()).await {
+        Ok(Ok(_)) => {
+            // One notification is sent per task; a single peek of the queue covers
+            // everything that has arrived so far.
+            while listener.next_buffered().is_some() {}
+        }
+        Ok(Err(e)) => {
+            log::error!("Error receiving queue notification: {}", e);
+            tokio::time::sleep(FALLBACK_POLL_INTERVAL).await
         }
+        Err(_) => log::debug!("No notification received, polling queue.."),
     }
 }
 
@@ -50,52 +171,189 @@ enum TryTaskError {
     DatabaseError(#[from] sqlx::Error),
     #[error("{0}")]
     EmailClientError(#[from] EmailClientError),
+    #[error("Failed to render email layout: {0}")]
+    TemplateError(#[from] tera::Error),
     #[error("No tasks ready to execute")]
     NoPendingTask,
+    #[error("Sending budget exhausted, retry in {0:?}")]
+    RateLimited(Duration),
+    #[error("Recipient of task {0} is suppressed")]
+    RecipientSuppressed(Uuid),
 }
 
 async fn try_execute_task<T>(
     email_client: &EmailClient<T>,
+    email_templates: &EmailTemplates,
+    token_signer: &TokenSigner,
     connection_pool: &PgPool,
-) -> Result<(), TryTaskError>
+    rate_limiter: &RateLimiter,
+    retry_policies: &RetryPolicies,
+    preferred: TaskPriority,
+) -> Result<TaskPriority, TryTaskError>
 where
     T: AsyncTransport + Sync + Send,
     T::Error: std::error::Error,
 {
     let mut transaction = connection_pool.begin().await?;
 
-    let task = email_delivery_queue::peek_task(&mut *transaction)
+    let task = email_delivery_queue::peek_task(&mut *transaction, preferred)
         .await?
         .ok_or(TryTaskError::NoPendingTask)?;
 
+    if task.suppressed {
+        email_delivery_queue::dead_letter_task(
+            &mut *transaction,
+            task.id,
+            "Recipient address is suppressed",
+        )
+        .await?;
+        transaction.commit().await?;
+        return Err(TryTaskError::RecipientSuppressed(task.id));
+    }
+
     let recipient = match SubscriberEmail::parse(task.email) {
         Ok(r) => r,
-        // TODO: if email is unparseable, immediately move task to dead
-        // letter queue once implemented instead of retrying + backing off
-        // like we do here
         Err(e) => {
-            email_delivery_queue::deprioritise_task(&mut *transaction, task.id, task.n_retries)
-                .await?;
+            retry_or_dead_letter(
+                &mut transaction,
+                &retry_policies.corrupted_data,
+                task.id,
+                task.n_retries,
+                task.backoff_ms,
+                &e.to_string(),
+            )
+            .await?;
             transaction.commit().await?;
             return Err(TryTaskError::CorruptedData(e));
         }
     };
 
-    if let Err(e) = email_client
-        .send_email(
-            &recipient,
-            &task.subject,
-            &email_template::html(task.subscriber_id, &task.email_html),
-            &email_template::text(task.subscriber_id, &task.email_text),
-        )
-        .await
-    {
-        email_delivery_queue::deprioritise_task(&mut *transaction, task.id, task.n_retries).await?;
-        transaction.commit().await?;
-        return Err(TryTaskError::EmailClientError(e));
+    if let Some(wait) = rate_limiter.try_acquire(connection_pool).await? {
+        // Release the task so it can be picked up once the budget has refilled
+        transaction.rollback().await?;
+        return Err(TryTaskError::RateLimited(wait));
     }
 
+    let content = EmailContent {
+        subject: task.subject,
+        preheader: task.preheader,
+        html: task.email_html,
+        text: task.email_text,
+    };
+    // The task is released when the transaction is dropped
+    let (html, text) = email_templates.render_layout(
+        &content,
+        &SubscriberLinks::new(token_signer, task.subscriber_id),
+        task.campaign_id,
+    )?;
+
+    let sent = match task.priority {
+        TaskPriority::Transactional => {
+            email_client
+                .send_email(&recipient, &content.subject, &html, &text)
+                .await
+        }
+        TaskPriority::Bulk => {
+            let unsubscribe_token = unsubscribe_token(token_signer, task.subscriber_id);
+            email_client
+                .send_bulk_email(
+                    &recipient,
+                    &content.subject,
+                    &html,
+                    &text,
+                    &one_click_unsubscribe_link(&unsubscribe_token),
+                    &unsubscribe_token,
+                )
+                .await
+        }
+    };
+
+    let message_id = match sent {
+        Ok(message_id) => message_id,
+        Err(e) => {
+            retry_or_dead_letter(
+                &mut transaction,
+                retry_policies.for_priority(task.priority),
+                task.id,
+                task.n_retries,
+                task.backoff_ms,
+                &e.to_string(),
+            )
+            .await?;
+            transaction.commit().await?;
+            return Err(TryTaskError::EmailClientError(e));
+        }
+    };
+
     email_delivery_queue::pop_task(&mut *transaction, task.id).await?;
+    email_delivery_queue::log_delivery(
+        &mut *transaction,
+        &message_id,
+        task.subscriber_id,
+        task.campaign_id,
+    )
+    .await?;
     transaction.commit().await?;
-    Ok(())
+    Ok(task.priority)
+}
+
+/// Schedule a failed task to be retried according to `policy`, or move it to
+/// the dead letter table if the policy has given up on it.
+async fn retry_or_dead_letter(
+    transaction: &mut Transaction<'_, Postgres>,
+    policy: &RetryPolicy,
+    task_id: Uuid,
+    n_retries: i32,
+    backoff_ms: Option<i64>,
+    reason: &str,
+) -> Result<(), sqlx::Error> {
+    let n_retries = u32::try_from(n_retries).unwrap_or(0);
+    let previous_delay = backoff_ms
+        .and_then(|ms| u64::try_from(ms).ok())
+        .map(Duration::from_millis);
+    let delay = policy.next_delay(n_retries, previous_delay, &mut rand::thread_rng());
+
+    match delay {
+        Some(delay) => {
+            email_delivery_queue::deprioritise_task(&mut **transaction, task_id, delay).await
+        }
+        None => {
+            let reason = format!("Gave up after {} attempts: {}", n_retries + 1, reason);
+            email_delivery_queue::dead_letter_task(&mut **transaction, task_id, &reason).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LaneScheduler, MAX_TRANSACTIONAL_STREAK};
+    use crate::email_delivery_queue::TaskPriority;
+
+    #[test]
+    fn transactional_lane_is_preferred_by_default() {
+        let scheduler = LaneScheduler::default();
+        assert_eq!(scheduler.preferred(), TaskPriority::Transactional);
+    }
+
+    #[test]
+    fn bulk_lane_is_served_after_a_streak_of_transactional_sends() {
+        let mut scheduler = LaneScheduler::default();
+        for _ in 0..MAX_TRANSACTIONAL_STREAK {
+            assert_eq!(scheduler.preferred(), TaskPriority::Transactional);
+            scheduler.record_sent(TaskPriority::Transactional);
+        }
+        assert_eq!(scheduler.preferred(), TaskPriority::Bulk);
+
+        scheduler.record_sent(TaskPriority::Bulk);
+        assert_eq!(scheduler.preferred(), TaskPriority::Transactional);
+    }
+
+    #[test]
+    fn bulk_lane_stays_preferred_until_bulk_mail_is_sent() {
+        let mut scheduler = LaneScheduler::default();
+        for _ in 0..MAX_TRANSACTIONAL_STREAK + 3 {
+            scheduler.record_sent(TaskPriority::Transactional);
+        }
+        assert_eq!(scheduler.preferred(), TaskPriority::Bulk);
+    }
 }
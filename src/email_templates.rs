@@ -0,0 +1,336 @@
+use crate::{
+    blog_post::BlogPostSummary,
+    signed_token::{TokenPurpose, TokenSigner},
+    util::app_url,
+};
+use chrono::TimeDelta;
+use tera::{Context, Tera};
+use uuid::Uuid;
+
+/// How long the unsubscribe and preferences links in an email keep working.
+/// Long enough that links in recent emails still work, but a leaked link
+/// doesn't last forever.
+pub const UNSUBSCRIBE_LINK_LIFETIME: TimeDelta = TimeDelta::days(90);
+
+/// The parts of an email rendered from one of the directories under
+/// `templates/email/`, before it is wrapped in the shared layout.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EmailContent {
+    pub subject: String,
+    /// Preview text shown by mail clients next to the subject line
+    pub preheader: String,
+    pub html: String,
+    pub text: String,
+}
+
+/// Renders the emails in `templates/email/`. Each email has a directory holding
+/// `subject.txt`, `preheader.txt`, `body.html` and `body.txt`, and every email
+/// is wrapped in `layout.html` / `layout.txt` when it is sent.
+#[derive(Debug, Clone)]
+pub struct EmailTemplates {
+    tera: Tera,
+}
+
+impl EmailTemplates {
+    pub fn load() -> Result<Self, anyhow::Error> {
+        let tera = Tera::new("templates/email/**/*")?;
+        Ok(Self { tera })
+    }
+
+    pub fn confirmation(&self, confirmation_link: &str) -> Result<EmailContent, tera::Error> {
+        let mut context = Context::new();
+        context.insert("confirmation_link", confirmation_link);
+        self.render_content("confirmation", &context)
+    }
+
+    /// Sent to a subscriber's new address when they ask to change it.
+    pub fn email_change(&self, confirmation_link: &str) -> Result<EmailContent, tera::Error> {
+        let mut context = Context::new();
+        context.insert("confirmation_link", confirmation_link);
+        self.render_content("email_change", &context)
+    }
+
+    pub fn welcome(&self) -> Result<EmailContent, tera::Error> {
+        self.render_content("welcome", &Context::new())
+    }
+
+    pub fn new_post(&self, post: &BlogPostSummary) -> Result<EmailContent, tera::Error> {
+        let mut context = Context::new();
+        context.insert("post", post);
+        self.render_content("new_post", &context)
+    }
+
+    /// Weekly round-up of `posts`, which must not be empty.
+    pub fn digest(&self, posts: &[BlogPostSummary]) -> Result<EmailContent, tera::Error> {
+        let mut context = Context::new();
+        context.insert("posts", posts);
+        self.render_content("digest", &context)
+    }
+
+    fn render_content(&self, email: &str, context: &Context) -> Result<EmailContent, tera::Error> {
+        let render = |part: &str| self.tera.render(&format!("{}/{}", email, part), context);
+        Ok(EmailContent {
+            subject: render("subject.txt")?.trim().to_string(),
+            preheader: render("preheader.txt")?.trim().to_string(),
+            html: render("body.html")?,
+            text: render("body.txt")?,
+        })
+    }
+
+    /// Wrap an email's content in the shared layout, returning the HTML and
+    /// plain text bodies to send with the given footer links. Campaign emails
+    /// link to their page in the newsletter archive.
+    pub fn render_layout(
+        &self,
+        content: &EmailContent,
+        links: &SubscriberLinks,
+        campaign_id: Option<Uuid>,
+    ) -> Result<(String, String), tera::Error> {
+        let mut context = Context::new();
+        context.insert("subject", &content.subject);
+        context.insert("preheader", &content.preheader);
+        context.insert("unsubscribe_link", &links.unsubscribe);
+        context.insert("preferences_link", &links.preferences);
+        if let Some(campaign_id) = campaign_id {
+            context.insert("view_in_browser_link", &newsletter_link(campaign_id));
+        }
+
+        context.insert("content", &content.html);
+        let html = self.tera.render("layout.html", &context)?;
+        context.insert("content", &content.text);
+        let text = self.tera.render("layout.txt", &context)?;
+        Ok((html, text))
+    }
+}
+
+/// Links in the footer of every email, which let the recipient manage their
+/// subscription without logging in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubscriberLinks {
+    pub unsubscribe: String,
+    pub preferences: String,
+}
+
+impl SubscriberLinks {
+    pub fn new(signer: &TokenSigner, subscriber_id: Uuid) -> Self {
+        Self {
+            unsubscribe: unsubscribe_link(signer, subscriber_id),
+            preferences: preferences_link(signer, subscriber_id),
+        }
+    }
+}
+
+/// Token letting a subscriber unsubscribe, valid as long as the links in
+/// their email.
+pub fn unsubscribe_token(signer: &TokenSigner, subscriber_id: Uuid) -> String {
+    signer.issue(
+        TokenPurpose::Unsubscribe,
+        subscriber_id,
+        UNSUBSCRIBE_LINK_LIFETIME,
+    )
+}
+
+/// Link to a page where the subscriber can confirm they want to unsubscribe.
+pub fn unsubscribe_link(signer: &TokenSigner, subscriber_id: Uuid) -> String {
+    let token = unsubscribe_token(signer, subscriber_id);
+    app_url(&format!("/subscriptions/unsubscribe?token={}", token))
+}
+
+/// Link to the subscriber's preference centre.
+pub fn preferences_link(signer: &TokenSigner, subscriber_id: Uuid) -> String {
+    let token = signer.issue(
+        TokenPurpose::ManagePreferences,
+        subscriber_id,
+        UNSUBSCRIBE_LINK_LIFETIME,
+    );
+    app_url(&format!("/subscriptions/preferences?token={}", token))
+}
+
+/// Web version of a campaign email, in the newsletter archive.
+pub fn newsletter_link(campaign_id: Uuid) -> String {
+    app_url(&format!("/newsletter/{}", campaign_id))
+}
+
+/// Link for RFC 8058 one-click unsubscribe requests made by mail clients,
+/// using a token from `unsubscribe_token`.
+pub fn one_click_unsubscribe_link(token: &str) -> String {
+    app_url(&format!(
+        "/subscriptions/unsubscribe/one-click?token={}",
+        token
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        one_click_unsubscribe_link, preferences_link, unsubscribe_link, unsubscribe_token,
+        EmailContent, EmailTemplates, SubscriberLinks,
+    };
+    use crate::blog_post::BlogPostSummary;
+    use crate::signed_token::{TokenPurpose, TokenSigner};
+    use crate::test_util::set_app_base_url;
+    use secrecy::Secret;
+    use uuid::Uuid;
+
+    fn templates() -> EmailTemplates {
+        EmailTemplates::load().expect("Failed to load email templates")
+    }
+
+    #[test]
+    fn every_email_renders_all_of_its_parts() {
+        let templates = templates();
+        for content in [
+            templates.confirmation("https://tld.com/confirm").unwrap(),
+            templates.email_change("https://tld.com/confirm").unwrap(),
+            templates.welcome().unwrap(),
+            templates.new_post(&post()).unwrap(),
+            templates.digest(&[post()]).unwrap(),
+        ] {
+            assert!(!content.subject.is_empty());
+            assert!(!content.subject.contains('\n'));
+            assert!(!content.preheader.is_empty());
+            assert!(!content.html.trim().is_empty());
+            assert!(!content.text.trim().is_empty());
+        }
+    }
+
+    fn post() -> BlogPostSummary {
+        BlogPostSummary {
+            title: "Post Title".into(),
+            date: Some("January 23, 2025".into()),
+            excerpt: "The first paragraph.".into(),
+            reading_time_minutes: 4,
+            hero_image: Some("https://tld.com/images/hero.png".into()),
+            url: "https://tld.com/blog/post-title".into(),
+            tags: vec![],
+        }
+    }
+
+    #[test]
+    fn new_post_email_is_built_from_post_summary() {
+        let post = post();
+        let content = templates().new_post(&post).unwrap();
+        assert_eq!(content.subject, "Post Title");
+        for body in [&content.html, &content.text] {
+            assert!(body.contains("January 23, 2025"));
+            assert!(body.contains("The first paragraph."));
+            assert!(body.contains("4 min read"));
+        }
+        assert!(content.html.contains(&tera::escape_html(&post.url)));
+        assert!(content.text.contains(&post.url));
+        assert!(content
+            .html
+            .contains(&tera::escape_html(post.hero_image.as_ref().unwrap())));
+    }
+
+    #[test]
+    fn digest_lists_every_post() {
+        let other = BlogPostSummary {
+            title: "Another Post".into(),
+            url: "https://tld.com/blog/another-post".into(),
+            ..post()
+        };
+        let content = templates().digest(&[post(), other.clone()]).unwrap();
+        assert_eq!(content.subject, "This week: Post Title and 1 more");
+        assert!(content.preheader.starts_with("2 new posts"));
+        for body in [&content.html, &content.text] {
+            assert!(body.contains("Post Title"));
+            assert!(body.contains("Another Post"));
+        }
+        assert!(content.text.contains(&other.url));
+
+        let content = templates().digest(&[post()]).unwrap();
+        assert_eq!(content.subject, "This week: Post Title");
+        assert!(content.preheader.starts_with("1 new post "));
+    }
+
+    #[test]
+    fn links_appear_in_both_bodies() {
+        let link = "https://tld.com/subscriptions/confirm?subscription_token=abc";
+        let content = templates().confirmation(link).unwrap();
+        assert!(content.html.contains(&tera::escape_html(link)));
+        assert!(content.text.contains(link));
+    }
+
+    #[test]
+    fn layout_wraps_content_with_preheader_and_subscriber_links() {
+        let content = EmailContent {
+            subject: "Subject".into(),
+            preheader: "Preview text".into(),
+            html: "<p>Hello & welcome</p>".into(),
+            text: "Hello & welcome".into(),
+        };
+
+        let links = SubscriberLinks {
+            unsubscribe: "https://tld.com/subscriptions/unsubscribe?token=abc".into(),
+            preferences: "https://tld.com/subscriptions/preferences?token=def".into(),
+        };
+        let (html, text) = templates().render_layout(&content, &links, None).unwrap();
+
+        // Content is already HTML, so it must not be escaped again
+        assert!(html.contains("<p>Hello & welcome</p>"));
+        assert!(html.contains("Preview text"));
+        assert!(text.starts_with("Hello & welcome"));
+        for link in [&links.unsubscribe, &links.preferences] {
+            assert!(html.contains(&tera::escape_html(link)));
+            assert!(text.contains(link.as_str()));
+        }
+        assert!(!html.contains("/newsletter/"));
+    }
+
+    #[test]
+    fn campaign_email_links_to_web_version() {
+        set_app_base_url();
+        let campaign_id = Uuid::new_v4();
+        let content = templates().welcome().unwrap();
+
+        let (html, text) = templates()
+            .render_layout(
+                &content,
+                &SubscriberLinks {
+                    unsubscribe: "https://tld.com/unsubscribe".into(),
+                    preferences: "https://tld.com/preferences".into(),
+                },
+                Some(campaign_id),
+            )
+            .unwrap();
+
+        let link = format!("https://tld.com/newsletter/{}", campaign_id);
+        assert!(html.contains(&tera::escape_html(&link)));
+        assert!(text.contains(&link));
+    }
+
+    #[test]
+    fn unsubscribe_links_carry_a_token_for_the_subscriber() {
+        set_app_base_url();
+        let signer = TokenSigner::new(&Secret::new("secret".to_string()));
+        let subscriber_id = Uuid::new_v4();
+
+        for link in [
+            unsubscribe_link(&signer, subscriber_id),
+            one_click_unsubscribe_link(&unsubscribe_token(&signer, subscriber_id)),
+        ] {
+            assert!(!link.contains(&format!("id={}", subscriber_id)));
+            let (_, token) = link.split_once("?token=").unwrap();
+            assert_eq!(
+                signer.verify(token, TokenPurpose::Unsubscribe),
+                Ok(subscriber_id)
+            );
+        }
+    }
+
+    #[test]
+    fn preferences_link_cannot_be_used_to_unsubscribe() {
+        set_app_base_url();
+        let signer = TokenSigner::new(&Secret::new("secret".to_string()));
+        let subscriber_id = Uuid::new_v4();
+
+        let link = preferences_link(&signer, subscriber_id);
+        let (_, token) = link.split_once("?token=").unwrap();
+        assert_eq!(
+            signer.verify(token, TokenPurpose::ManagePreferences),
+            Ok(subscriber_id)
+        );
+        assert!(signer.verify(token, TokenPurpose::Unsubscribe).is_err());
+    }
+}
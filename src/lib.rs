@@ -1,7 +1,23 @@
+pub mod blog_post;
+pub mod bot_protection;
+pub mod bounces;
+mod csrf;
+pub mod digest;
 mod domain;
+pub mod email_campaigns;
 pub mod email_delivery_queue;
 pub mod email_delivery_worker;
+pub mod email_templates;
 mod flash_message;
 pub mod routes;
+pub mod signed_token;
+pub mod signup_rate_limit;
 pub mod ssr;
+pub mod subscribers;
+pub mod subscription_events;
+pub mod subscription_tokens;
+pub mod suppressions;
+#[cfg(test)]
+mod test_util;
+pub mod topics;
 pub mod util;
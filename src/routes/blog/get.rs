@@ -1,7 +1,11 @@
-use crate::{flash_message::Flash, ssr::SsrCommon, util::e500};
+use crate::{
+    bot_protection::BotProtection, csrf::Csrf, flash_message::Flash, ssr::SsrCommon, topics,
+    util::e500,
+};
 use actix_session::Session;
 use actix_web::{web, HttpResponse};
 use serde::Serialize;
+use sqlx::PgPool;
 use std::fs;
 
 #[derive(Serialize, Debug)]
@@ -11,8 +15,34 @@ struct BlogPost {
 }
 pub async fn get(
     ssr: web::Data<SsrCommon>,
+    pool: web::Data<PgPool>,
+    bot_protection: web::Data<BotProtection>,
     session: Session,
 ) -> Result<HttpResponse, actix_web::Error> {
+    let flash_message = session.get_flash();
+    if flash_message.is_some() {
+        session.clear_flash();
+    }
+    let html = render_blog(
+        &ssr,
+        &pool,
+        &bot_protection,
+        &session,
+        flash_message.as_deref(),
+    )
+    .await?;
+    Ok(HttpResponse::Ok().content_type("text/html").body(html))
+}
+
+/// The blog page, with `flash` shown under the sign-up form. Each render
+/// serves the form with a fresh bot protection challenge.
+pub async fn render_blog(
+    ssr: &SsrCommon,
+    pool: &PgPool,
+    bot_protection: &BotProtection,
+    session: &Session,
+    flash: Option<&str>,
+) -> Result<String, actix_web::Error> {
     let mut post_titles: Vec<String> = fs::read_dir("blog")?
         .filter_map(|e| {
             e.ok()
@@ -35,15 +65,18 @@ pub async fn get(
         }
     }
 
-    let html = if let Some(flash_message) = session.get_flash() {
-        session.clear_flash();
-        ssr.as_ref().clone().with_context("flash", &flash_message)
+    let topics = topics::all_topics(pool).await.map_err(e500)?;
+    // The sign-up form's challenge is bound to the session's CSRF token
+    let session_token = session.csrf_token().map_err(e500)?;
+    let ssr = ssr.clone().with_csrf_token(session).map_err(e500)?;
+    if let Some(flash) = flash {
+        ssr.with_context("flash", flash)
     } else {
-        ssr.as_ref().clone()
+        ssr
     }
     .with_context("posts", &posts)
+    .with_context("topics", &topics)
+    .with_context("challenge", &bot_protection.challenge(&session_token))
     .render("blog.html")
-    .map_err(e500)?;
-
-    Ok(HttpResponse::Ok().content_type("text/html").body(html))
+    .map_err(e500)
 }
@@ -1,2 +1,2 @@
 mod get;
-pub use get::get;
+pub use get::{get, render_blog};
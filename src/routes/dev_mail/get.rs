@@ -0,0 +1,48 @@
+use crate::{email_delivery_worker::MailCatcher, ssr::SsrCommon, util::e500};
+use actix_web::{error::ErrorNotFound, web, HttpResponse};
+
+pub async fn inbox(
+    ssr: web::Data<SsrCommon>,
+    mail_catcher: web::Data<MailCatcher>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let emails: Vec<_> = mail_catcher.emails().iter().map(|e| e.view()).collect();
+    let html = ssr
+        .as_ref()
+        .clone()
+        .with_context("emails", &emails)
+        .render("dev_mail.html")
+        .map_err(e500)?;
+    Ok(HttpResponse::Ok().content_type("text/html").body(html))
+}
+
+pub async fn message(
+    ssr: web::Data<SsrCommon>,
+    mail_catcher: web::Data<MailCatcher>,
+    id: web::Path<usize>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let email = mail_catcher
+        .email(id.into_inner())
+        .ok_or_else(|| ErrorNotFound("No such email"))?;
+    let html = ssr
+        .as_ref()
+        .clone()
+        .with_context("email", &email.view())
+        .render("dev_mail_message.html")
+        .map_err(e500)?;
+    Ok(HttpResponse::Ok().content_type("text/html").body(html))
+}
+
+/// The HTML part of a captured email, displayed in an iframe by `message`.
+pub async fn message_html(
+    mail_catcher: web::Data<MailCatcher>,
+    id: web::Path<usize>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let email = mail_catcher
+        .email(id.into_inner())
+        .ok_or_else(|| ErrorNotFound("No such email"))?;
+    let body = email.view().html.unwrap_or_default();
+    // Follow links in the top-level window rather than inside the iframe
+    Ok(HttpResponse::Ok()
+        .content_type("text/html")
+        .body(format!("<base target=\"_top\">{}", body)))
+}
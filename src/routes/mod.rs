@@ -1,3 +1,6 @@
 pub mod blog;
+pub mod dev_mail;
 pub mod health_check;
+pub mod newsletter;
 pub mod subscriptions;
+pub mod webhooks;
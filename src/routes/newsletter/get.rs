@@ -0,0 +1,77 @@
+use crate::{email_campaigns, ssr::SsrCommon, util::e500};
+use actix_web::{error::ErrorNotFound, web, HttpResponse};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+#[derive(Serialize, Debug)]
+struct ArchiveEntry {
+    path: String, // e.g. "/newsletter/<campaign id>"
+    subject: String,
+    preheader: String,
+    date: String,
+}
+
+/// Dates are shown the same way as on blog posts, e.g. "January 23, 2025".
+pub(crate) fn format_date(date: DateTime<Utc>) -> String {
+    date.format("%B %-d, %Y").to_string()
+}
+
+pub async fn archive(
+    ssr: web::Data<SsrCommon>,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let issues: Vec<_> = email_campaigns::archived_campaigns(&**pool)
+        .await
+        .map_err(e500)?
+        .into_iter()
+        .map(|campaign| ArchiveEntry {
+            path: format!("/newsletter/{}", campaign.id),
+            subject: campaign.subject,
+            preheader: campaign.preheader,
+            date: format_date(campaign.created_at),
+        })
+        .collect();
+
+    let html = ssr
+        .as_ref()
+        .clone()
+        .with_context("issues", &issues)
+        .render("newsletter.html")
+        .map_err(e500)?;
+    Ok(HttpResponse::Ok().content_type("text/html").body(html))
+}
+
+pub async fn issue(
+    ssr: web::Data<SsrCommon>,
+    pool: web::Data<PgPool>,
+    id: web::Path<Uuid>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let campaign = email_campaigns::archived_campaign(&**pool, id.into_inner())
+        .await
+        .map_err(e500)?
+        .ok_or_else(|| ErrorNotFound("No such newsletter"))?;
+
+    let html = ssr
+        .as_ref()
+        .clone()
+        .with_context("subject", &campaign.subject)
+        .with_context("date", &format_date(campaign.created_at))
+        .with_context("content", &campaign.email_html)
+        .render("newsletter_issue.html")
+        .map_err(e500)?;
+    Ok(HttpResponse::Ok().content_type("text/html").body(html))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format_date;
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn dates_match_blog_post_format() {
+        let date = Utc.with_ymd_and_hms(2025, 1, 3, 18, 30, 0).unwrap();
+        assert_eq!(format_date(date), "January 3, 2025");
+    }
+}
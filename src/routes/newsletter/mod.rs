@@ -0,0 +1,3 @@
+mod get;
+pub(crate) use get::format_date;
+pub use get::{archive, issue};
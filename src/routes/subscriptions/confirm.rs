@@ -1,7 +1,22 @@
-use crate::{email_delivery_queue, flash_message::Flash, util::error_chain_fmt};
+use super::subscribe::enqueue_confirmation_email;
+use crate::{
+    csrf::CsrfForm,
+    email_delivery_queue::{self, TaskPriority},
+    email_templates::EmailTemplates,
+    flash_message::Flash,
+    ssr::SsrCommon,
+    subscribers::SubscriptionStatus,
+    subscription_events::{record_event, NewSubscriptionEvent, SubscriptionEventKind},
+    subscription_tokens::{self, TokenStatus},
+    suppressions,
+    util::error_chain_fmt,
+};
 use actix_session::Session;
-use actix_web::{http::header::LOCATION, http::StatusCode, web, HttpResponse, ResponseError};
+use actix_web::{
+    http::header::LOCATION, http::StatusCode, web, HttpRequest, HttpResponse, ResponseError,
+};
 use anyhow::Context;
+use chrono::Utc;
 use lettre::AsyncTransport;
 use sqlx::{PgPool, Postgres, Transaction};
 use tracing;
@@ -12,9 +27,13 @@ pub struct Parameters {
     subscription_token: String,
 }
 
+#[derive(serde::Deserialize)]
+pub struct ConfirmForm {
+    subscription_token: String,
+}
+
 #[derive(thiserror::Error)]
 pub enum SubscriptionConfirmError {
-    InvalidSubscriptionToken,
     #[error(transparent)]
     UnexpectedError(#[from] anyhow::Error),
 }
@@ -25,50 +44,94 @@ impl std::fmt::Debug for SubscriptionConfirmError {
     }
 }
 
-impl std::fmt::Display for SubscriptionConfirmError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "An error was encountered while trying to confirm a subscription."
-        )
-    }
-}
 impl ResponseError for SubscriptionConfirmError {
     fn status_code(&self) -> StatusCode {
         match self {
-            Self::InvalidSubscriptionToken => StatusCode::UNAUTHORIZED,
             Self::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 }
 
-pub async fn confirm<T>(
+/// Title of the pages explaining why a link couldn't be used.
+const PAGE_TITLE: &str = "Confirm your subscription";
+
+/// Landing page for the link in confirmation emails. Mail scanners which
+/// prefetch links would confirm everyone if following the link were enough, so
+/// confirming takes a further POST.
+pub async fn confirm_form(
+    ssr: web::Data<SsrCommon>,
     pool: web::Data<PgPool>,
     parameters: web::Query<Parameters>,
     session: Session,
+) -> Result<HttpResponse, SubscriptionConfirmError> {
+    let record = subscription_tokens::get_token(&**pool, &parameters.subscription_token)
+        .await
+        .context("Failed to look up subscription token")?;
+    if record.map(|record| record.status(Utc::now())) != Some(TokenStatus::Valid) {
+        return unusable_token_page(&ssr, &pool, &session, &parameters.subscription_token).await;
+    }
+
+    let html = ssr
+        .as_ref()
+        .clone()
+        .with_csrf_token(&session)
+        .context("Failed to set session state")?
+        .with_context("subscription_token", &parameters.subscription_token)
+        .render("confirm.html")
+        .context("Failed to render page")?;
+    Ok(HttpResponse::Ok().content_type("text/html").body(html))
+}
+
+pub async fn confirm<T>(
+    request: HttpRequest,
+    ssr: web::Data<SsrCommon>,
+    pool: web::Data<PgPool>,
+    email_templates: web::Data<EmailTemplates>,
+    CsrfForm(form): CsrfForm<ConfirmForm>,
+    session: Session,
 ) -> Result<HttpResponse, SubscriptionConfirmError>
 where
     T: AsyncTransport + Sync + Send,
     T::Error: std::error::Error,
 {
-    let subscriber_id = get_subscriber_id_from_token(&pool, &parameters.subscription_token)
-        .await
-        .context("Failed to look up subscriber id in subscription_tokens table")?
-        .ok_or(SubscriptionConfirmError::InvalidSubscriptionToken)?;
-
     let mut transaction = pool
         .begin()
         .await
         .context("Failed to acquire a Postgres connection from the pool")?;
 
-    confirm_subscriber(&mut transaction, subscriber_id).await?;
+    let Some(subscriber_id) =
+        subscription_tokens::use_token(&mut transaction, &form.subscription_token)
+            .await
+            .context("Failed to look up subscription token")?
+    else {
+        transaction.rollback().await.context("Transaction failed")?;
+        return unusable_token_page(&ssr, &pool, &session, &form.subscription_token).await;
+    };
+
+    if !confirm_subscriber(&mut transaction, subscriber_id).await? {
+        transaction.rollback().await.context("Transaction failed")?;
+        return Ok(ssr
+            .render_message(
+                StatusCode::GONE,
+                PAGE_TITLE,
+                "This subscription has ended. Please sign up again on the blog.",
+            )
+            .context("Failed to render page")?);
+    }
+    let event = NewSubscriptionEvent::new(SubscriptionEventKind::Confirmed).request(&request);
+    record_event(&mut *transaction, subscriber_id, &event)
+        .await
+        .context("Failed to record confirmation")?;
+
+    let welcome_email = email_templates
+        .welcome()
+        .context("Failed to render welcome email")?;
 
     email_delivery_queue::push_task(
         &mut *transaction,
         subscriber_id,
-        "Welcome!",
-        "<p>Your subscription to my blog is now confirmed. Welcome!</p>",
-        "Your subscription to my blog is now confirmed. Welcome!",
+        TaskPriority::Transactional,
+        &welcome_email,
     )
     .await
     .with_context(|| String::from("Failed to send email"))?;
@@ -87,37 +150,150 @@ where
         .finish())
 }
 
-#[tracing::instrument(skip_all)]
-async fn get_subscriber_id_from_token(
+/// Explain why a token couldn't be used, offering a new link if it expired.
+async fn unusable_token_page(
+    ssr: &SsrCommon,
     pool: &PgPool,
+    session: &Session,
     token: &str,
-) -> Result<Option<Uuid>, sqlx::Error> {
-    let result = sqlx::query!(
-        r#"SELECT subscriber_id FROM subscription_tokens 
-        WHERE subscription_token = $1
-        "#,
-        token
+) -> Result<HttpResponse, SubscriptionConfirmError> {
+    let record = subscription_tokens::get_token(pool, token)
+        .await
+        .context("Failed to look up subscription token")?;
+
+    match record.map(|record| record.status(Utc::now())) {
+        None => Ok(ssr
+            .render_message(
+                StatusCode::UNAUTHORIZED,
+                PAGE_TITLE,
+                "This confirmation link isn't valid. Please sign up again on the blog.",
+            )
+            .context("Failed to render page")?),
+        Some(TokenStatus::Used) => Ok(ssr
+            .render_message(
+                StatusCode::OK,
+                PAGE_TITLE,
+                "This link has already been used - your subscription is confirmed.",
+            )
+            .context("Failed to render page")?),
+        // A token which was valid a moment ago has expired since
+        Some(TokenStatus::Expired | TokenStatus::Valid) => {
+            let html = ssr
+                .clone()
+                .with_csrf_token(session)
+                .context("Failed to set session state")?
+                .with_context("subscription_token", token)
+                .render("confirmation_expired.html")
+                .context("Failed to render page")?;
+            Ok(HttpResponse::Gone().content_type("text/html").body(html))
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct ResendForm {
+    subscription_token: String,
+}
+
+/// Send a new confirmation link to whoever an expired token was sent to.
+pub async fn resend_confirmation(
+    request: HttpRequest,
+    pool: web::Data<PgPool>,
+    email_templates: web::Data<EmailTemplates>,
+    CsrfForm(form): CsrfForm<ResendForm>,
+    session: Session,
+) -> Result<HttpResponse, SubscriptionConfirmError> {
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+
+    let record = subscription_tokens::get_token(&mut *transaction, &form.subscription_token)
+        .await
+        .context("Failed to look up subscription token")?;
+    let status = match &record {
+        Some(record) => subscription_status(&mut transaction, record.subscriber_id).await?,
+        None => None,
+    };
+
+    let flash = match (record, status) {
+        (Some(record), Some(SubscriptionStatus::Pending)) => {
+            let subscription_token =
+                subscription_tokens::rotate_token(&mut transaction, record.subscriber_id)
+                    .await
+                    .context("Failed to rotate subscription token")?;
+            enqueue_confirmation_email(
+                &mut *transaction,
+                &email_templates,
+                record.subscriber_id,
+                &subscription_token,
+            )
+            .await
+            .context("Error sending confirmation")?;
+            let event = NewSubscriptionEvent::new(SubscriptionEventKind::ConfirmationResent)
+                .request(&request)
+                .source("expired-link");
+            record_event(&mut *transaction, record.subscriber_id, &event)
+                .await
+                .context("Failed to record resent confirmation")?;
+            "A new confirmation email has been sent to your inbox."
+        }
+        (Some(_), Some(SubscriptionStatus::Active)) => "Your subscription is already confirmed.",
+        _ => "Sorry - that link is no longer valid. Please sign up again.",
+    };
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to the database")?;
+
+    session
+        .set_flash(flash)
+        .context("Failed to set session state")?;
+    Ok(HttpResponse::SeeOther()
+        .insert_header((LOCATION, "/blog"))
+        .finish())
+}
+
+#[tracing::instrument(skip_all)]
+async fn subscription_status(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> Result<Option<SubscriptionStatus>, anyhow::Error> {
+    sqlx::query_scalar!(
+        r#"SELECT status AS "status: SubscriptionStatus" FROM subscriptions WHERE id = $1"#,
+        subscriber_id
     )
-    .fetch_optional(pool)
-    .await?;
-    Ok(result.map(|record| record.subscriber_id))
+    .fetch_optional(&mut **transaction)
+    .await
+    .context("Failed to look up subscriber")
 }
 
+/// Activate a pending subscription. Returns false if the subscriber has left
+/// since the token was issued.
 #[tracing::instrument(skip_all)]
 async fn confirm_subscriber(
     transaction: &mut Transaction<'_, Postgres>,
     subscriber_id: Uuid,
-) -> Result<(), anyhow::Error> {
-    sqlx::query!(
+) -> Result<bool, anyhow::Error> {
+    let email = sqlx::query_scalar!(
         r#"
         UPDATE subscriptions
-        SET confirmed = true 
-        WHERE id = $1"#,
+        SET status = 'active'
+        WHERE id = $1 AND status IN ('pending', 'active')
+        RETURNING email"#,
         subscriber_id
     )
-    .execute(&mut **transaction) // Rust :)
+    .fetch_optional(&mut **transaction) // Rust :)
     .await
     .context("Failed to register subscriber confirmation in database")?;
+    let Some(email) = email else {
+        return Ok(false);
+    };
+
+    // Confirming is a fresh opt-in, so an earlier unsubscribe no longer applies
+    suppressions::lift_unsubscribed(&mut **transaction, &email)
+        .await
+        .context("Failed to update suppression list")?;
 
-    Ok(())
+    Ok(true)
 }
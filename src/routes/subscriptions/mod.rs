@@ -1,7 +1,12 @@
 mod confirm;
+mod preferences;
 mod subscribe;
 mod unsubscribe;
 
-pub use confirm::confirm;
+pub use confirm::{confirm, confirm_form, resend_confirmation};
+pub use preferences::{
+    change_email, confirm_email_change, confirm_email_change_form, preferences_page,
+    update_preferences,
+};
 pub use subscribe::subscribe;
-pub use unsubscribe::unsubscribe;
+pub use unsubscribe::{unsubscribe, unsubscribe_form, unsubscribe_one_click};
@@ -0,0 +1,611 @@
+use crate::{
+    csrf::CsrfForm,
+    digest::DeliveryMode,
+    domain::SubscriberEmail,
+    email_delivery_queue::{self, TaskPriority},
+    email_templates::{EmailTemplates, UNSUBSCRIBE_LINK_LIFETIME},
+    flash_message::Flash,
+    routes::newsletter::format_date,
+    signed_token::{TokenPurpose, TokenSigner},
+    signup_rate_limit::{client_ip, SignupRateLimiter},
+    ssr::SsrCommon,
+    subscription_events::{
+        describe_preferences, record_event, NewSubscriptionEvent, SubscriptionEventKind,
+    },
+    subscription_tokens::{generate_subscription_token, CONFIRMATION_TOKEN_LIFETIME},
+    suppressions, topics,
+    util::{app_url, error_chain_fmt, form_with_repeated},
+};
+use actix_session::Session;
+use actix_web::{
+    http::header::LOCATION, http::StatusCode, web, HttpRequest, HttpResponse, ResponseError,
+};
+use anyhow::Context;
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::Deserialize;
+use sqlx::{Executor, PgPool, Postgres, Transaction};
+use tracing_log::log;
+use uuid::Uuid;
+
+/// The longest a subscriber can pause delivery for in one go.
+const MAX_PAUSE_WEEKS: u32 = 26;
+
+#[derive(thiserror::Error)]
+pub enum PreferencesError {
+    #[error("{0}")]
+    ValidationError(String),
+    #[error("Invalid form: {0}")]
+    InvalidForm(#[from] serde::de::value::Error),
+    #[error(transparent)]
+    UnexpectedError(#[from] anyhow::Error),
+}
+
+impl std::fmt::Debug for PreferencesError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for PreferencesError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::ValidationError(_) | Self::InvalidForm(_) => StatusCode::BAD_REQUEST,
+            Self::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct TokenParameters {
+    token: String,
+}
+
+#[derive(serde::Deserialize)]
+pub struct ChangeEmailForm {
+    token: String,
+    email: String,
+}
+
+/// What to do about pausing delivery.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+enum Pause {
+    /// Leave any current pause as it is
+    #[default]
+    Keep,
+    Resume,
+    ForWeeks(u32),
+}
+
+impl TryFrom<String> for Pause {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.as_str() {
+            "keep" => Ok(Self::Keep),
+            "0" => Ok(Self::Resume),
+            weeks => weeks
+                .parse()
+                .ok()
+                .filter(|weeks| (1..=MAX_PAUSE_WEEKS).contains(weeks))
+                .map(Self::ForWeeks)
+                .ok_or_else(|| format!("Invalid pause: {}", value)),
+        }
+    }
+}
+
+impl Pause {
+    fn paused_until(
+        self,
+        current: Option<DateTime<Utc>>,
+        now: DateTime<Utc>,
+    ) -> Option<DateTime<Utc>> {
+        match self {
+            Self::Keep => current,
+            Self::Resume => None,
+            Self::ForWeeks(weeks) => Some(now + TimeDelta::weeks(i64::from(weeks))),
+        }
+    }
+}
+
+/// The main preferences form. Topics are checkboxes sharing a name, so are
+/// read separately with `form_with_repeated`.
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+struct PreferencesForm {
+    token: String,
+    #[serde(default)]
+    delivery_mode: DeliveryMode,
+    #[serde(default)]
+    pause: Pause,
+}
+
+#[derive(serde::Serialize)]
+struct TopicChoice {
+    slug: String,
+    name: String,
+    selected: bool,
+}
+
+struct Subscriber {
+    email: String,
+    delivery_mode: DeliveryMode,
+    paused_until: Option<DateTime<Utc>>,
+}
+
+#[tracing::instrument(skip_all)]
+async fn get_subscriber<'a, T>(
+    executor: T,
+    subscriber_id: Uuid,
+) -> Result<Option<Subscriber>, sqlx::Error>
+where
+    T: Executor<'a, Database = Postgres>,
+{
+    sqlx::query_as!(
+        Subscriber,
+        r#"
+        SELECT email, delivery_mode AS "delivery_mode: DeliveryMode", paused_until
+        FROM subscriptions WHERE id = $1 AND status IN ('pending', 'active')
+        "#,
+        subscriber_id
+    )
+    .fetch_optional(executor)
+    .await
+}
+
+/// Title of the pages explaining why something couldn't be done.
+const PAGE_TITLE: &str = "Manage preferences";
+
+fn invalid_link_page(ssr: &SsrCommon) -> Result<HttpResponse, PreferencesError> {
+    Ok(ssr
+        .render_message(
+            StatusCode::BAD_REQUEST,
+            PAGE_TITLE,
+            "This link is invalid or has expired. \
+            Please use the link in a more recent email from the blog.",
+        )
+        .context("Failed to render page")?)
+}
+
+fn not_subscribed_page(ssr: &SsrCommon) -> Result<HttpResponse, PreferencesError> {
+    Ok(ssr
+        .render_message(
+            StatusCode::NOT_FOUND,
+            PAGE_TITLE,
+            "You're no longer subscribed. You can sign up again on the blog.",
+        )
+        .context("Failed to render page")?)
+}
+
+fn back_to_preferences(token: &str) -> HttpResponse {
+    HttpResponse::SeeOther()
+        .insert_header((
+            LOCATION,
+            format!("/subscriptions/preferences?token={}", token),
+        ))
+        .finish()
+}
+
+/// Linked from the footer of every email, so subscribers can manage their
+/// subscription without an account.
+pub async fn preferences_page(
+    ssr: web::Data<SsrCommon>,
+    token_signer: web::Data<TokenSigner>,
+    pool: web::Data<PgPool>,
+    parameters: web::Query<TokenParameters>,
+    session: Session,
+) -> Result<HttpResponse, PreferencesError> {
+    let Ok(subscriber_id) = token_signer.verify(&parameters.token, TokenPurpose::ManagePreferences)
+    else {
+        return invalid_link_page(&ssr);
+    };
+    let Some(subscriber) = get_subscriber(&**pool, subscriber_id)
+        .await
+        .context("Failed to look up subscriber")?
+    else {
+        return not_subscribed_page(&ssr);
+    };
+
+    let chosen = topics::subscriber_topics(&**pool, subscriber_id)
+        .await
+        .context("Failed to look up subscriber's topics")?;
+    let topics: Vec<_> = topics::all_topics(&**pool)
+        .await
+        .context("Failed to look up topics")?
+        .into_iter()
+        .map(|topic| TopicChoice {
+            selected: chosen.contains(&topic.slug),
+            slug: topic.slug,
+            name: topic.name,
+        })
+        .collect();
+
+    let unsubscribe_token = token_signer.issue(
+        TokenPurpose::Unsubscribe,
+        subscriber_id,
+        UNSUBSCRIBE_LINK_LIFETIME,
+    );
+
+    let mut ssr = ssr
+        .as_ref()
+        .clone()
+        .with_csrf_token(&session)
+        .context("Failed to set session state")?
+        .with_context("token", &parameters.token)
+        .with_context("unsubscribe_token", &unsubscribe_token)
+        .with_context("email", &subscriber.email)
+        .with_context("delivery_mode", &subscriber.delivery_mode)
+        .with_context("topics", &topics);
+    if let Some(paused_until) = subscriber.paused_until.filter(|until| *until > Utc::now()) {
+        ssr = ssr.with_context("paused_until", &format_date(paused_until));
+    }
+    if let Some(flash) = session.get_flash() {
+        session.clear_flash();
+        ssr = ssr.with_context("flash", &flash);
+    }
+    let html = ssr
+        .render("preferences.html")
+        .context("Failed to render page")?;
+    Ok(HttpResponse::Ok().content_type("text/html").body(html))
+}
+
+/// Save delivery mode, pause and topics.
+pub async fn update_preferences(
+    request: HttpRequest,
+    ssr: web::Data<SsrCommon>,
+    token_signer: web::Data<TokenSigner>,
+    pool: web::Data<PgPool>,
+    CsrfForm(fields): CsrfForm<Vec<(String, String)>>,
+    session: Session,
+) -> Result<HttpResponse, PreferencesError> {
+    let (form, topics): (PreferencesForm, _) = form_with_repeated(fields, "topic")?;
+    let Ok(subscriber_id) = token_signer.verify(&form.token, TokenPurpose::ManagePreferences)
+    else {
+        return invalid_link_page(&ssr);
+    };
+
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    let Some(subscriber) = get_subscriber(&mut *transaction, subscriber_id)
+        .await
+        .context("Failed to look up subscriber")?
+    else {
+        return not_subscribed_page(&ssr);
+    };
+
+    let paused_until = form.pause.paused_until(subscriber.paused_until, Utc::now());
+    sqlx::query!(
+        r#"
+        UPDATE subscriptions SET delivery_mode = $2, paused_until = $3
+        WHERE id = $1
+        "#,
+        subscriber_id,
+        form.delivery_mode as DeliveryMode,
+        paused_until
+    )
+    .execute(&mut *transaction)
+    .await
+    .context("Failed to update preferences")?;
+    let topics = topics::set_subscriber_topics(&mut transaction, subscriber_id, &topics)
+        .await
+        .context("Failed to update topics")?;
+    let event = NewSubscriptionEvent::new(SubscriptionEventKind::PreferencesChanged)
+        .request(&request)
+        .source("preference-centre")
+        .detail(describe_preferences(
+            form.delivery_mode,
+            &topics,
+            paused_until,
+        ));
+    record_event(&mut *transaction, subscriber_id, &event)
+        .await
+        .context("Failed to record preference change")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to the database")?;
+
+    session
+        .set_flash("Your preferences have been saved.")
+        .context("Error setting session state")?;
+    Ok(back_to_preferences(&form.token))
+}
+
+/// Record a request to change address, expiring any earlier ones, and return
+/// the token which confirms it.
+#[tracing::instrument(skip_all)]
+async fn store_email_change_request(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    new_email: &SubscriberEmail,
+) -> Result<String, sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE email_change_requests
+        SET expires_at = LEAST(expires_at, NOW())
+        WHERE subscriber_id = $1 AND used_at IS NULL
+        "#,
+        subscriber_id
+    )
+    .execute(&mut **transaction)
+    .await?;
+
+    let token = generate_subscription_token();
+    sqlx::query!(
+        r#"
+        INSERT INTO email_change_requests (token, subscriber_id, new_email, expires_at)
+        VALUES ($1, $2, $3, $4)
+        "#,
+        token,
+        subscriber_id,
+        new_email.as_ref(),
+        Utc::now() + CONFIRMATION_TOKEN_LIFETIME
+    )
+    .execute(&mut **transaction)
+    .await?;
+    Ok(token)
+}
+
+/// Send a confirmation link to the new address. The address only changes once
+/// it is followed, so a typo can't redirect someone's subscription.
+#[allow(clippy::too_many_arguments)]
+pub async fn change_email(
+    request: HttpRequest,
+    ssr: web::Data<SsrCommon>,
+    token_signer: web::Data<TokenSigner>,
+    pool: web::Data<PgPool>,
+    email_templates: web::Data<EmailTemplates>,
+    rate_limiter: web::Data<SignupRateLimiter>,
+    CsrfForm(form): CsrfForm<ChangeEmailForm>,
+    session: Session,
+) -> Result<HttpResponse, PreferencesError> {
+    let Ok(subscriber_id) = token_signer.verify(&form.token, TokenPurpose::ManagePreferences)
+    else {
+        return invalid_link_page(&ssr);
+    };
+    let Ok(new_email) = SubscriberEmail::parse(form.email) else {
+        session
+            .set_flash("That doesn't look like a valid email address.")
+            .context("Error setting session state")?;
+        return Ok(back_to_preferences(&form.token));
+    };
+
+    // Each change sends an email to the new address, so is limited like a
+    // sign-up
+    let allowed = rate_limiter
+        .try_record(&pool, client_ip(&request).as_deref(), new_email.as_ref())
+        .await
+        .context("Failed to check sign-up rate limits")?;
+    if let Err(exceeded) = allowed {
+        log::warn!("Email change rate limit exceeded ({:?})", exceeded);
+        return Ok(ssr
+            .render_message(
+                StatusCode::TOO_MANY_REQUESTS,
+                PAGE_TITLE,
+                "Too many attempts to change your email address. \
+                Please wait a while before trying again.",
+            )
+            .context("Failed to render page")?);
+    }
+
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+    if get_subscriber(&mut *transaction, subscriber_id)
+        .await
+        .context("Failed to look up subscriber")?
+        .is_none()
+    {
+        return not_subscribed_page(&ssr);
+    }
+
+    let change_token = store_email_change_request(&mut transaction, subscriber_id, &new_email)
+        .await
+        .context("Failed to store email change request")?;
+    let confirmation_link = app_url(&format!(
+        "/subscriptions/preferences/email/confirm?token={}",
+        change_token
+    ));
+    let content = email_templates
+        .email_change(&confirmation_link)
+        .context("Failed to render email change confirmation")?;
+    email_delivery_queue::push_task_to(
+        &mut *transaction,
+        subscriber_id,
+        new_email.as_ref(),
+        TaskPriority::Transactional,
+        &content,
+    )
+    .await
+    .context("Failed to queue email change confirmation")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to the database")?;
+
+    session
+        .set_flash("Check your new inbox and follow the link to confirm the change.")
+        .context("Error setting session state")?;
+    Ok(back_to_preferences(&form.token))
+}
+
+fn unusable_change_link_page(ssr: &SsrCommon) -> Result<HttpResponse, PreferencesError> {
+    Ok(ssr
+        .render_message(
+            StatusCode::GONE,
+            PAGE_TITLE,
+            "This link is invalid, has expired or has already been used. \
+            You can ask for a new one from your preferences.",
+        )
+        .context("Failed to render page")?)
+}
+
+/// Landing page for the link sent to a new address. As with confirming a
+/// subscription, the change takes a further POST so that link scanners don't
+/// make it.
+pub async fn confirm_email_change_form(
+    ssr: web::Data<SsrCommon>,
+    pool: web::Data<PgPool>,
+    parameters: web::Query<TokenParameters>,
+    session: Session,
+) -> Result<HttpResponse, PreferencesError> {
+    let new_email = sqlx::query_scalar!(
+        r#"
+        SELECT new_email FROM email_change_requests
+        WHERE token = $1 AND used_at IS NULL AND expires_at > NOW()
+        "#,
+        parameters.token
+    )
+    .fetch_optional(&**pool)
+    .await
+    .context("Failed to look up email change request")?;
+    let Some(new_email) = new_email else {
+        return unusable_change_link_page(&ssr);
+    };
+
+    let html = ssr
+        .as_ref()
+        .clone()
+        .with_csrf_token(&session)
+        .context("Failed to set session state")?
+        .with_context("token", &parameters.token)
+        .with_context("new_email", &new_email)
+        .render("confirm_email_change.html")
+        .context("Failed to render page")?;
+    Ok(HttpResponse::Ok().content_type("text/html").body(html))
+}
+
+pub async fn confirm_email_change(
+    request: HttpRequest,
+    ssr: web::Data<SsrCommon>,
+    token_signer: web::Data<TokenSigner>,
+    pool: web::Data<PgPool>,
+    CsrfForm(form): CsrfForm<TokenParameters>,
+    session: Session,
+) -> Result<HttpResponse, PreferencesError> {
+    let mut transaction = pool
+        .begin()
+        .await
+        .context("Failed to acquire a Postgres connection from the pool")?;
+
+    let change = sqlx::query!(
+        r#"
+        UPDATE email_change_requests
+        SET used_at = NOW()
+        WHERE token = $1 AND used_at IS NULL AND expires_at > NOW()
+        RETURNING subscriber_id, new_email,
+            email_address_hash(new_email) AS "new_email_hash!"
+        "#,
+        form.token
+    )
+    .fetch_optional(&mut *transaction)
+    .await
+    .context("Failed to look up email change request")?;
+    let Some(change) = change else {
+        return unusable_change_link_page(&ssr);
+    };
+
+    let updated = sqlx::query!(
+        r#"UPDATE subscriptions SET email = $2 WHERE id = $1"#,
+        change.subscriber_id,
+        change.new_email
+    )
+    .execute(&mut *transaction)
+    .await;
+    if let Err(e) = updated {
+        if e.as_database_error()
+            .is_some_and(|e| e.code() == Some("23505".into()))
+        {
+            log::info!("Email change rejected as the address is already subscribed");
+            return Ok(ssr
+                .render_message(
+                    StatusCode::CONFLICT,
+                    PAGE_TITLE,
+                    "That address is already subscribed to the blog.",
+                )
+                .context("Failed to render page")?);
+        }
+        return Err(anyhow::Error::new(e)
+            .context("Failed to change subscriber's email")
+            .into());
+    }
+
+    // Confirming the new address is a fresh opt-in for it
+    suppressions::lift_unsubscribed(&mut *transaction, &change.new_email)
+        .await
+        .context("Failed to update suppression list")?;
+    // Only the hash, as the log can't be edited to erase an address later
+    let event = NewSubscriptionEvent::new(SubscriptionEventKind::EmailChanged)
+        .request(&request)
+        .source("preference-centre")
+        .detail(format!("New address hash: {}", change.new_email_hash));
+    record_event(&mut *transaction, change.subscriber_id, &event)
+        .await
+        .context("Failed to record email change")?;
+    transaction
+        .commit()
+        .await
+        .context("Failed to commit SQL transaction to the database")?;
+
+    session
+        .set_flash("Your email address has been changed.")
+        .context("Error setting session state")?;
+    let preferences_token = token_signer.issue(
+        TokenPurpose::ManagePreferences,
+        change.subscriber_id,
+        UNSUBSCRIBE_LINK_LIFETIME,
+    );
+    Ok(back_to_preferences(&preferences_token))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Pause, PreferencesForm};
+    use crate::{digest::DeliveryMode, util::form_with_repeated};
+    use chrono::{TimeDelta, Utc};
+    use claims::assert_err;
+    use serde::de::value::Error as ValueError;
+
+    fn parse(body: &str) -> Result<(PreferencesForm, Vec<String>), ValueError> {
+        let fields: Vec<(String, String)> = serde_urlencoded::from_str(body).unwrap();
+        form_with_repeated(fields, "topic")
+    }
+
+    #[test]
+    fn repeated_topics_are_collected() {
+        let (form, topics) = parse(
+            "token=abc&delivery_mode=weekly_digest&pause=4&topic=rust&topic=career&csrf_token=x",
+        )
+        .unwrap();
+        assert_eq!(
+            form,
+            PreferencesForm {
+                token: "abc".into(),
+                delivery_mode: DeliveryMode::WeeklyDigest,
+                pause: Pause::ForWeeks(4),
+            }
+        );
+        assert_eq!(topics, vec!["rust", "career"]);
+    }
+
+    #[test]
+    fn invalid_fields_are_rejected() {
+        assert_err!(parse("delivery_mode=immediate"));
+        assert_err!(parse("token=abc&delivery_mode=hourly"));
+        assert_err!(parse("token=abc&pause=1000"));
+    }
+
+    #[test]
+    fn pause_can_be_kept_extended_or_lifted() {
+        let now = Utc::now();
+        let current = Some(now + TimeDelta::days(3));
+        assert_eq!(Pause::Keep.paused_until(current, now), current);
+        assert_eq!(Pause::Resume.paused_until(current, now), None);
+        assert_eq!(
+            Pause::ForWeeks(2).paused_until(current, now),
+            Some(now + TimeDelta::weeks(2))
+        );
+    }
+}
@@ -1,15 +1,27 @@
 use crate::{
+    bot_protection::{BotCheckFailure, BotProtection, Submission},
+    csrf::{Csrf, CsrfForm},
+    digest::DeliveryMode,
     domain::{InvalidEmailError, SubscriberEmail},
-    email_delivery_queue,
+    email_delivery_queue::{self, TaskPriority},
+    email_templates::EmailTemplates,
     flash_message::Flash,
-    util::{error_chain_fmt, read_env_or_panic},
+    routes::blog::render_blog,
+    signup_rate_limit::{client_ip, SignupRateLimiter},
+    ssr::SsrCommon,
+    subscribers::SubscriptionStatus,
+    subscription_events::{
+        describe_preferences, record_event, NewSubscriptionEvent, SubscriptionEventKind,
+    },
+    subscription_tokens, suppressions, topics,
+    util::{app_url, error_chain_fmt, form_with_repeated},
 };
 use actix_session::Session;
-use actix_web::{http::header::LOCATION, http::StatusCode, web, HttpResponse, ResponseError};
+use actix_web::{
+    http::header::LOCATION, http::StatusCode, web, HttpRequest, HttpResponse, ResponseError,
+};
 use anyhow::Context;
 use chrono::Utc;
-use rand::distributions::Alphanumeric;
-use rand::{thread_rng, Rng};
 use sqlx::{Executor, PgPool, Postgres, Transaction};
 use tracing_log::log;
 use uuid::Uuid;
@@ -18,6 +30,8 @@ use uuid::Uuid;
 pub enum SubscribeError {
     #[error("{0}")]
     ValidationError(#[from] InvalidEmailError),
+    #[error("Invalid form: {0}")]
+    InvalidForm(#[from] serde::de::value::Error),
     #[error(transparent)]
     UnexpectedError(#[from] anyhow::Error),
 }
@@ -31,15 +45,42 @@ impl std::fmt::Debug for SubscribeError {
 impl ResponseError for SubscribeError {
     fn status_code(&self) -> StatusCode {
         match self {
-            Self::ValidationError(_) => StatusCode::BAD_REQUEST,
+            Self::ValidationError(_) | Self::InvalidForm(_) => StatusCode::BAD_REQUEST,
             Self::UnexpectedError(_) => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 }
 
+const SIGNUP_SUCCESS_MESSAGE: &str =
+    "Check your inbox for a confirmation email and follow the link to complete your registration.";
+
 #[derive(serde::Deserialize)]
 pub struct FormData {
     email: String,
+    #[serde(default)]
+    delivery_mode: DeliveryMode,
+    /// Honeypot, hidden from people by the form's styling
+    #[serde(default)]
+    website: String,
+    #[serde(default)]
+    form_token: String,
+    #[serde(default)]
+    proof_of_work: String,
+    /// Which form the sign-up came from, for the consent record
+    #[serde(default)]
+    source: String,
+}
+
+impl FormData {
+    fn bot_check_submission<'a>(&'a self, session_token: &'a str) -> Submission<'a> {
+        Submission {
+            honeypot: &self.website,
+            form_token: &self.form_token,
+            proof_of_work: &self.proof_of_work,
+            email: &self.email,
+            session_token,
+        }
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -54,17 +95,19 @@ pub enum InsertSubscriberError {
 async fn insert_subscriber(
     transaction: &mut Transaction<'_, Postgres>,
     subscriber: &SubscriberEmail,
+    delivery_mode: DeliveryMode,
 ) -> Result<Uuid, InsertSubscriberError> {
     let id = Uuid::new_v4();
     let query = sqlx::query!(
         r#"
-            INSERT INTO subscriptions (id, email, subscribed_at, confirmed)
-            VALUES ($1, $2, $3, $4)
+            INSERT INTO subscriptions (id, email, subscribed_at, status, delivery_mode)
+            VALUES ($1, $2, $3, $4, $5)
             "#,
         id,
         subscriber.as_ref(),
         Utc::now(),
-        false
+        SubscriptionStatus::Pending as SubscriptionStatus,
+        delivery_mode as DeliveryMode
     );
 
     match transaction.execute(query).await {
@@ -81,130 +124,173 @@ async fn insert_subscriber(
     }
 }
 
-pub struct StoreTokenError(sqlx::Error);
-
-impl std::fmt::Debug for StoreTokenError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        error_chain_fmt(self, f)
-    }
-}
-
-impl std::fmt::Display for StoreTokenError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "A database error was encountered while trying to store a subscription token"
-        )
-    }
-}
-
-impl std::error::Error for StoreTokenError {
-    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-        Some(&self.0)
-    }
+struct ExistingSubscriber {
+    id: Uuid,
+    status: SubscriptionStatus,
 }
 
 #[tracing::instrument(skip_all)]
-pub async fn store_token(
+async fn get_subscriber_by_email(
     transaction: &mut Transaction<'_, Postgres>,
-    subscriber_id: Uuid,
-    subscription_token: &str,
-) -> Result<(), StoreTokenError> {
-    let query = sqlx::query!(
-        r#"
-            INSERT INTO subscription_tokens (subscription_token, subscriber_id)
-            VALUES ($1, $2)
-        "#,
-        subscription_token,
-        subscriber_id
-    );
-
-    transaction.execute(query).await.map_err(StoreTokenError)?;
-
-    Ok(())
-}
-
-fn generate_subscription_token() -> String {
-    let mut rng = thread_rng();
-    std::iter::repeat_with(|| rng.sample(Alphanumeric))
-        .map(char::from)
-        .take(25)
-        .collect()
-}
-
-struct SubscriptionTokenRecord {
-    subscription_token: String,
-    subscriber_id: Uuid,
+    subscriber_email: &SubscriberEmail,
+) -> Result<Option<ExistingSubscriber>, sqlx::Error> {
+    sqlx::query_as!(
+        ExistingSubscriber,
+        r#"SELECT id, status AS "status: SubscriptionStatus" FROM subscriptions WHERE email = $1"#,
+        subscriber_email.as_ref()
+    )
+    .fetch_optional(&mut **transaction)
+    .await
 }
 
+/// Sign a former subscriber up again. They have to confirm, as if new.
+/// Returns the topics stored for them.
 #[tracing::instrument(skip_all)]
-async fn get_subscription_token_details_from_subscriber_email(
-    connection_pool: &PgPool,
-    subscriber_email: &SubscriberEmail,
-) -> Result<SubscriptionTokenRecord, anyhow::Error> {
-    sqlx::query_as!(
-        SubscriptionTokenRecord,
+async fn restart_subscription(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    delivery_mode: DeliveryMode,
+    topics: &[String],
+) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query!(
         r#"
-        SELECT subscription_tokens.*
-        FROM subscriptions JOIN subscription_tokens
-        ON subscriptions.id = subscription_tokens.subscriber_id
-        WHERE subscriptions.email = $1
+        UPDATE subscriptions
+        SET status = 'pending', unsubscribed_at = NULL, subscribed_at = NOW(),
+            delivery_mode = $2, paused_until = NULL
+        WHERE id = $1
         "#,
-        subscriber_email.as_ref()
+        subscriber_id,
+        delivery_mode as DeliveryMode
     )
-    .fetch_optional(connection_pool)
-    .await?
-    .ok_or(anyhow::anyhow!(format!(
-        "Unable to find subscription token for email {}",
-        subscriber_email
-    )))
+    .execute(&mut **transaction)
+    .await?;
+    topics::set_subscriber_topics(transaction, subscriber_id, topics).await
 }
 
-async fn enqueue_confirmation_email<'a, T>(
+pub(super) async fn enqueue_confirmation_email<'a, T>(
     executor: T,
+    email_templates: &EmailTemplates,
     subscriber_id: Uuid,
     subscription_token: &str,
-) -> Result<(), sqlx::Error>
+) -> Result<(), anyhow::Error>
 where
     T: Executor<'a, Database = Postgres>,
 {
-    let app_base_url = read_env_or_panic("APP_BASE_URL");
-
-    let confirmation_link = format!(
-        "{}/subscriptions/confirm?subscription_token={}",
-        app_base_url, subscription_token
-    );
-
-    let html_content = &format!(
-        "Thanks for signing up to my blog!
-         <br /> Click <a href=\"{}\">here</a>
-         to confirm your subscription.",
-        confirmation_link
-    );
+    let confirmation_link = app_url(&format!(
+        "/subscriptions/confirm?subscription_token={}",
+        subscription_token
+    ));
 
-    let text_content = &format!(
-        "Thanks for signing up to my blog!\nVisit {} to confirm your subscription.",
-        confirmation_link
-    );
+    let content = email_templates
+        .confirmation(&confirmation_link)
+        .context("Failed to render confirmation email")?;
 
     email_delivery_queue::push_task(
         executor,
         subscriber_id,
-        "Please confirm your subscription.",
-        html_content,
-        text_content,
+        TaskPriority::Transactional,
+        &content,
     )
-    .await?;
+    .await
+    .context("Failed to queue confirmation email")?;
 
     Ok(())
 }
 
+// Each argument is an extractor, so bundling them would only obscure things
+#[allow(clippy::too_many_arguments)]
 pub async fn subscribe<T>(
-    form: web::Form<FormData>,
+    request: HttpRequest,
+    CsrfForm(fields): CsrfForm<Vec<(String, String)>>,
     connection_pool: web::Data<PgPool>,
+    email_templates: web::Data<EmailTemplates>,
+    rate_limiter: web::Data<SignupRateLimiter>,
+    bot_protection: web::Data<BotProtection>,
+    ssr: web::Data<SsrCommon>,
     session: Session,
 ) -> Result<HttpResponse, SubscribeError> {
-    let subscriber_email = SubscriberEmail::parse(form.0.email)?;
+    let (form, topics): (FormData, _) = form_with_repeated(fields, "topic")?;
+    let session_token = session
+        .csrf_token()
+        .context("Error reading session state")?;
+    match bot_protection.check(&form.bot_check_submission(&session_token)) {
+        Ok(()) => {}
+        // Bots which fill in every field are told they succeeded, so they
+        // have no reason to adapt
+        Err(BotCheckFailure::Honeypot) => {
+            log::warn!("Discarding sign-up with honeypot filled in");
+            session
+                .set_flash(SIGNUP_SUCCESS_MESSAGE)
+                .context("Error setting session state")?;
+            return Ok(HttpResponse::SeeOther()
+                .insert_header((LOCATION, "/blog"))
+                .finish());
+        }
+        // Anything else could be a person who was unlucky, e.g. with a stale
+        // page, so let them try again with a fresh form
+        Err(e) => {
+            log::warn!("Rejecting sign-up which failed bot check: {}", e);
+            let html = render_blog(
+                &ssr,
+                &connection_pool,
+                &bot_protection,
+                &session,
+                Some("Sorry - we couldn't verify your sign-up. Please try again."),
+            )
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to render blog page: {}", e))?;
+            return Ok(HttpResponse::BadRequest()
+                .content_type("text/html")
+                .body(html));
+        }
+    }
+
+    let FormData {
+        email,
+        delivery_mode,
+        source,
+        ..
+    } = form;
+    let subscriber_email = SubscriberEmail::parse(email)?;
+
+    let client_ip = client_ip(&request);
+    let allowed = rate_limiter
+        .try_record(
+            &connection_pool,
+            client_ip.as_deref(),
+            subscriber_email.as_ref(),
+        )
+        .await
+        .context("Failed to check sign-up rate limits")?;
+    if let Err(exceeded) = allowed {
+        log::warn!("Sign-up rate limit exceeded ({:?})", exceeded);
+        let html = render_blog(
+            &ssr,
+            &connection_pool,
+            &bot_protection,
+            &session,
+            Some("Too many sign-up attempts. Please wait a while before trying again."),
+        )
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to render blog page: {}", e))?;
+        return Ok(HttpResponse::TooManyRequests()
+            .content_type("text/html")
+            .body(html));
+    }
+
+    let suppression =
+        suppressions::suppression_reason(&**connection_pool, subscriber_email.as_ref())
+            .await
+            .context("Failed to check the suppression list")?;
+    if suppression.is_some_and(|reason| !reason.allows_resubscribe()) {
+        log::info!("Rejecting sign-up from suppressed address");
+        session
+            .set_flash("Sorry - we can't send email to this address. Please get in touch if you think this is a mistake.")
+            .context("Error setting session state")?;
+        return Ok(HttpResponse::SeeOther()
+            .insert_header((LOCATION, "/blog"))
+            .finish());
+    }
 
     let mut transaction = connection_pool
         .begin()
@@ -212,51 +298,106 @@ pub async fn subscribe<T>(
         .context("Failed to acquire a Postgres connection from the pool")?;
 
     log::info!("Attempting to insert subscriber...");
-    match insert_subscriber(&mut transaction, &subscriber_email).await {
+    match insert_subscriber(&mut transaction, &subscriber_email, delivery_mode).await {
         Ok(subscriber_id) => {
             log::info!("Succeeded!");
-            let subscription_token = generate_subscription_token();
-            log::info!("Storing token...");
-            store_token(&mut transaction, subscriber_id, &subscription_token)
+            let topics = topics::set_subscriber_topics(&mut transaction, subscriber_id, &topics)
+                .await
+                .context("Failed to store subscriber's topics")?;
+            let event = NewSubscriptionEvent::new(SubscriptionEventKind::SignedUp)
+                .request(&request)
+                .source(&source)
+                .detail(describe_preferences(delivery_mode, &topics, None));
+            record_event(&mut *transaction, subscriber_id, &event)
                 .await
-                .context("Failed to insert subscription token in the database")?;
+                .context("Failed to record sign-up")?;
+            log::info!("Storing token...");
+            let subscription_token =
+                subscription_tokens::rotate_token(&mut transaction, subscriber_id)
+                    .await
+                    .context("Failed to insert subscription token in the database")?;
 
-            log::info!("Committing transaction...");
             log::info!("Pushing confirmation email task onto queue...");
+            enqueue_confirmation_email(
+                &mut *transaction,
+                &email_templates,
+                subscriber_id,
+                &subscription_token,
+            )
+            .await
+            .context("Error sending confirmation")?;
 
-            enqueue_confirmation_email(&mut *transaction, subscriber_id, &subscription_token)
-                .await
-                .context("Error sending confirmation")?;
-
+            log::info!("Committing transaction...");
             transaction
                 .commit()
                 .await
                 .context("Failed to commit SQL transaction to the database")?;
 
-            session.set_flash(
-                "Check your inbox for a confirmation email and follow the link to complete your registration."
-            ).context("Error setting session state")?;
+            session
+                .set_flash(SIGNUP_SUCCESS_MESSAGE)
+                .context("Error setting session state")?;
         }
-        // Resend existing confirmation token
+        // Send a fresh confirmation link, unless there's nothing left to confirm.
+        // Former subscribers start again from pending.
         Err(InsertSubscriberError::DuplicateEmail) => {
             log::info!("Duplicate email! Rolling back...");
             transaction.rollback().await.context("Transaction failed")?;
-            let subscription_token_record = get_subscription_token_details_from_subscriber_email(
-                &connection_pool,
-                &subscriber_email,
-            )
-            .await?;
 
-            log::info!("Resending confirmation email...");
-
-            enqueue_confirmation_email(
-                &**connection_pool,
-                subscription_token_record.subscriber_id,
-                &subscription_token_record.subscription_token,
-            )
-            .await
-            .context("Error sending confirmation")?;
+            let mut transaction = connection_pool
+                .begin()
+                .await
+                .context("Failed to acquire a Postgres connection from the pool")?;
+            let subscriber = get_subscriber_by_email(&mut transaction, &subscriber_email)
+                .await
+                .context("Failed to look up existing subscriber")?
+                .ok_or(anyhow::anyhow!(
+                    "Unable to find subscriber with email {}",
+                    subscriber_email
+                ))?;
+
+            if subscriber.status.has_left() {
+                log::info!("Restarting former subscriber's subscription...");
+                let topics =
+                    restart_subscription(&mut transaction, subscriber.id, delivery_mode, &topics)
+                        .await
+                        .context("Failed to restart subscription")?;
+                let event = NewSubscriptionEvent::new(SubscriptionEventKind::SignedUp)
+                    .request(&request)
+                    .source(&source)
+                    .detail(describe_preferences(delivery_mode, &topics, None));
+                record_event(&mut *transaction, subscriber.id, &event)
+                    .await
+                    .context("Failed to record sign-up")?;
+            } else if subscriber.status == SubscriptionStatus::Pending {
+                // Their original choices stand until they confirm
+                let event = NewSubscriptionEvent::new(SubscriptionEventKind::ConfirmationResent)
+                    .request(&request)
+                    .source(&source);
+                record_event(&mut *transaction, subscriber.id, &event)
+                    .await
+                    .context("Failed to record resent confirmation")?;
+            }
+            if subscriber.status != SubscriptionStatus::Active {
+                log::info!("Rotating token and resending confirmation email...");
+                let subscription_token =
+                    subscription_tokens::rotate_token(&mut transaction, subscriber.id)
+                        .await
+                        .context("Failed to rotate subscription token")?;
+                enqueue_confirmation_email(
+                    &mut *transaction,
+                    &email_templates,
+                    subscriber.id,
+                    &subscription_token,
+                )
+                .await
+                .context("Error sending confirmation")?;
+            }
+            transaction
+                .commit()
+                .await
+                .context("Failed to commit SQL transaction to the database")?;
 
+            // The same message either way, so as not to reveal who is subscribed
             session
                 .set_flash("Email already registered. A new confirmation email has been sent to your inbox in case you haven't confirmed already.")
                 .context("Error setting session state")?;
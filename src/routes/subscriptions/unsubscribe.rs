@@ -1,10 +1,18 @@
-use crate::{flash_message::Flash, util::error_chain_fmt};
+use crate::{
+    csrf::CsrfForm,
+    flash_message::Flash,
+    signed_token::{TokenPurpose, TokenSigner},
+    ssr::SsrCommon,
+    subscribers::remove_subscriber,
+    subscription_events::{NewSubscriptionEvent, SubscriptionEventKind},
+    util::error_chain_fmt,
+};
 use actix_session::Session;
-use actix_web::{http::header::LOCATION, http::StatusCode, web, HttpResponse, ResponseError};
+use actix_web::{
+    http::header::LOCATION, http::StatusCode, web, HttpRequest, HttpResponse, ResponseError,
+};
 use anyhow::Context;
-use lettre::AsyncTransport;
 use sqlx::PgPool;
-use uuid::Uuid;
 
 #[derive(thiserror::Error)]
 pub enum UnsubscribeError {
@@ -30,45 +38,68 @@ impl ResponseError for UnsubscribeError {
 }
 
 #[derive(serde::Deserialize)]
-pub struct Parameters {
-    id: Uuid,
+pub struct TokenParameters {
+    token: String,
 }
 
-#[tracing::instrument(skip_all)]
-async fn remove_subscriber(
-    connection_pool: &PgPool,
-    subscriber_id: Uuid,
-) -> Result<(), sqlx::Error> {
-    sqlx::query!(
-        r#"
-        WITH deleted_subscriptions AS (
-            DELETE FROM subscriptions 
-            WHERE id = $1
-            RETURNING id
+/// Body of an RFC 8058 one-click unsubscribe request.
+#[derive(serde::Deserialize)]
+pub struct OneClickForm {
+    #[serde(rename = "List-Unsubscribe")]
+    list_unsubscribe: String,
+}
+
+fn invalid_link_page(ssr: &SsrCommon) -> Result<HttpResponse, UnsubscribeError> {
+    Ok(ssr
+        .render_message(
+            StatusCode::BAD_REQUEST,
+            "Unsubscribe",
+            "This unsubscribe link is invalid or has expired. \
+            Please use the link in a more recent email from the blog.",
         )
-        DELETE FROM subscription_tokens 
-        WHERE subscriber_id IN (
-            SELECT id 
-            FROM deleted_subscriptions
-        ) "#,
-        subscriber_id
-    )
-    .execute(connection_pool)
-    .await?;
+        .context("Failed to render page")?)
+}
 
-    Ok(())
+/// Linked from the footer of every email. Unsubscribing needs a further POST, so
+/// that link scanners following the link don't unsubscribe anyone.
+pub async fn unsubscribe_form(
+    ssr: web::Data<SsrCommon>,
+    token_signer: web::Data<TokenSigner>,
+    parameters: web::Query<TokenParameters>,
+    session: Session,
+) -> Result<HttpResponse, UnsubscribeError> {
+    if token_signer
+        .verify(&parameters.token, TokenPurpose::Unsubscribe)
+        .is_err()
+    {
+        return invalid_link_page(&ssr);
+    }
+    let html = ssr
+        .as_ref()
+        .clone()
+        .with_csrf_token(&session)
+        .context("Failed to set session state")?
+        .with_context("token", &parameters.token)
+        .render("unsubscribe.html")
+        .context("Failed to render page")?;
+    Ok(HttpResponse::Ok().content_type("text/html").body(html))
 }
 
-pub async fn unsubscribe<T>(
+pub async fn unsubscribe(
+    request: HttpRequest,
+    ssr: web::Data<SsrCommon>,
     connection_pool: web::Data<PgPool>,
-    parameters: web::Query<Parameters>,
+    token_signer: web::Data<TokenSigner>,
+    CsrfForm(form): CsrfForm<TokenParameters>,
     session: Session,
-) -> Result<HttpResponse, UnsubscribeError>
-where
-    T: AsyncTransport + Sync + Send,
-    T::Error: std::error::Error,
-{
-    remove_subscriber(&connection_pool, parameters.id).await?;
+) -> Result<HttpResponse, UnsubscribeError> {
+    let Ok(subscriber_id) = token_signer.verify(&form.token, TokenPurpose::Unsubscribe) else {
+        return invalid_link_page(&ssr);
+    };
+    let event = NewSubscriptionEvent::new(SubscriptionEventKind::Unsubscribed)
+        .request(&request)
+        .source("unsubscribe-page");
+    remove_subscriber(&connection_pool, subscriber_id, &event).await?;
     session
         .set_flash("Successfully unsubscribed!")
         .context("Error setting session state")?;
@@ -76,3 +107,28 @@ where
         .insert_header((LOCATION, "/blog"))
         .finish())
 }
+
+/// Unsubscribe requests sent by mail clients via the `List-Unsubscribe` header.
+/// There is no user to show a page to, so no session is used and an empty
+/// response is returned.
+pub async fn unsubscribe_one_click(
+    request: HttpRequest,
+    connection_pool: web::Data<PgPool>,
+    token_signer: web::Data<TokenSigner>,
+    parameters: web::Query<TokenParameters>,
+    form: web::Form<OneClickForm>,
+) -> Result<HttpResponse, UnsubscribeError> {
+    if form.list_unsubscribe != "One-Click" {
+        return Ok(HttpResponse::BadRequest().finish());
+    }
+    let Ok(subscriber_id) = token_signer.verify(&parameters.token, TokenPurpose::Unsubscribe)
+    else {
+        return Ok(HttpResponse::BadRequest().finish());
+    };
+    // Sent by the mail client, so the request details are the mail provider's
+    let event = NewSubscriptionEvent::new(SubscriptionEventKind::Unsubscribed)
+        .request(&request)
+        .source("list-unsubscribe");
+    remove_subscriber(&connection_pool, subscriber_id, &event).await?;
+    Ok(HttpResponse::Ok().finish())
+}
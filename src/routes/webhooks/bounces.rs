@@ -0,0 +1,67 @@
+use crate::{
+    bounces::{parse_report, process_bounce},
+    util::{constant_time_eq, error_chain_fmt},
+};
+use actix_web::{http::StatusCode, web, HttpRequest, HttpResponse, ResponseError};
+use lettre::Address;
+use secrecy::{ExposeSecret, Secret};
+use sqlx::PgPool;
+
+/// Configuration for the inbound bounce webhook, which is only served when a
+/// token is configured.
+pub struct BounceWebhookConfig {
+    /// Bearer token the mail provider authenticates with
+    pub token: Secret<String>,
+    /// Address bounces are returned to, used to decode VERP return paths
+    pub bounce_address: Option<Address>,
+}
+
+#[derive(thiserror::Error)]
+pub enum BounceWebhookError {
+    #[error("Missing or invalid webhook token")]
+    Unauthorized,
+    #[error("Request body is not a bounce or complaint report")]
+    NotABounce,
+    #[error("{0}")]
+    DatabaseError(#[from] sqlx::Error),
+}
+
+impl std::fmt::Debug for BounceWebhookError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for BounceWebhookError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::NotABounce => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::DatabaseError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Receives a raw DSN or ARF report, e.g. forwarded by an inbound mail
+/// provider, and suppresses the subscriber it is about.
+pub async fn bounces(
+    request: HttpRequest,
+    body: web::Bytes,
+    config: web::Data<BounceWebhookConfig>,
+    connection_pool: web::Data<PgPool>,
+) -> Result<HttpResponse, BounceWebhookError> {
+    let token = request
+        .headers()
+        .get("Authorization")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .ok_or(BounceWebhookError::Unauthorized)?;
+    if !constant_time_eq(token.as_bytes(), config.token.expose_secret().as_bytes()) {
+        return Err(BounceWebhookError::Unauthorized);
+    }
+
+    let report = parse_report(&body).ok_or(BounceWebhookError::NotABounce)?;
+    // Reports which can't be traced are logged and skipped
+    process_bounce(&connection_pool, &report, config.bounce_address.as_ref()).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
@@ -0,0 +1,3 @@
+mod bounces;
+
+pub use bounces::{bounces, BounceWebhookConfig};
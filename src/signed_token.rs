@@ -0,0 +1,256 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64, Engine};
+use chrono::{DateTime, TimeDelta, Utc};
+use hmac::{Hmac, Mac};
+use secrecy::{ExposeSecret, Secret};
+use sha2::Sha256;
+use uuid::Uuid;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// What a token lets its bearer do. A token issued for one purpose is rejected
+/// for any other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenPurpose {
+    Unsubscribe,
+    ManagePreferences,
+}
+
+impl TokenPurpose {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Unsubscribe => "unsubscribe",
+            Self::ManagePreferences => "manage-preferences",
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum TokenError {
+    #[error("Malformed token")]
+    Malformed,
+    #[error("Token signature is invalid")]
+    InvalidSignature,
+    #[error("Token has expired")]
+    Expired,
+}
+
+/// Signs tokens of the form `<field>.<field>...<signature>` with
+/// `APP_HMAC_SECRET`. Each kind of token has its own `context`, which is signed
+/// along with the fields so that a token of one kind is never accepted as
+/// another. Values which must match but aren't carried in the token itself can
+/// be signed as `bound` fields.
+#[derive(Clone)]
+pub struct HmacSigner {
+    key: Secret<String>,
+    context: &'static str,
+}
+
+impl HmacSigner {
+    pub fn new(secret: &Secret<String>, context: &'static str) -> Self {
+        Self {
+            key: secret.clone(),
+            context,
+        }
+    }
+
+    fn mac(&self, bound: &[&str], fields: &[&str]) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(self.key.expose_secret().as_bytes())
+            .expect("HMAC accepts keys of any length");
+        mac.update(self.context.as_bytes());
+        for field in bound.iter().chain(fields) {
+            mac.update(b"|");
+            mac.update(field.as_bytes());
+        }
+        mac
+    }
+
+    /// A token carrying `fields`, none of which may contain a `.`.
+    pub fn sign(&self, bound: &[&str], fields: &[&str]) -> String {
+        debug_assert!(fields.iter().all(|field| !field.contains('.')));
+        let signature = self.mac(bound, fields).finalize().into_bytes();
+        let mut token = fields.join(".");
+        token.push('.');
+        token.push_str(&BASE64.encode(signature));
+        token
+    }
+
+    /// The fields of a token signed by us with the same `bound` values.
+    pub fn verify<'t>(&self, token: &'t str, bound: &[&str]) -> Result<Vec<&'t str>, TokenError> {
+        let (fields, signature) = token.rsplit_once('.').ok_or(TokenError::Malformed)?;
+        let fields: Vec<&str> = fields.split('.').collect();
+        let signature = BASE64
+            .decode(signature)
+            .map_err(|_| TokenError::Malformed)?;
+        // Compared in constant time
+        self.mac(bound, &fields)
+            .verify_slice(&signature)
+            .map_err(|_| TokenError::InvalidSignature)?;
+        Ok(fields)
+    }
+}
+
+/// Issues and verifies tokens which identify a subscriber without exposing
+/// their id to anyone able to guess or alter it. Tokens have the form
+/// `<subscriber id>.<expiry as unix seconds>.<signature>`, where the signature
+/// is an HMAC-SHA256 over the purpose, id and expiry keyed with
+/// `APP_HMAC_SECRET`.
+#[derive(Clone)]
+pub struct TokenSigner {
+    signer: HmacSigner,
+}
+
+impl TokenSigner {
+    pub fn new(secret: &Secret<String>) -> Self {
+        Self {
+            signer: HmacSigner::new(secret, "subscriber-token-v1"),
+        }
+    }
+
+    /// A token for `subscriber_id` which is valid for `lifetime`.
+    pub fn issue(&self, purpose: TokenPurpose, subscriber_id: Uuid, lifetime: TimeDelta) -> String {
+        self.issue_at(purpose, subscriber_id, Utc::now() + lifetime)
+    }
+
+    fn issue_at(
+        &self,
+        purpose: TokenPurpose,
+        subscriber_id: Uuid,
+        expires_at: DateTime<Utc>,
+    ) -> String {
+        self.signer.sign(
+            &[purpose.as_str()],
+            &[
+                &subscriber_id.to_string(),
+                &expires_at.timestamp().to_string(),
+            ],
+        )
+    }
+
+    /// The subscriber a token was issued for, if it was issued by us for
+    /// `purpose` and hasn't expired.
+    pub fn verify(&self, token: &str, purpose: TokenPurpose) -> Result<Uuid, TokenError> {
+        self.verify_at(token, purpose, Utc::now())
+    }
+
+    fn verify_at(
+        &self,
+        token: &str,
+        purpose: TokenPurpose,
+        now: DateTime<Utc>,
+    ) -> Result<Uuid, TokenError> {
+        let fields = self.signer.verify(token, &[purpose.as_str()])?;
+        let [id, expires_at] = fields[..] else {
+            return Err(TokenError::Malformed);
+        };
+        let subscriber_id = Uuid::parse_str(id).map_err(|_| TokenError::Malformed)?;
+        let expires_at: i64 = expires_at.parse().map_err(|_| TokenError::Malformed)?;
+
+        if now.timestamp() >= expires_at {
+            return Err(TokenError::Expired);
+        }
+        Ok(subscriber_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HmacSigner, TokenError, TokenPurpose, TokenSigner};
+    use chrono::{TimeDelta, Utc};
+    use claims::assert_err_eq;
+    use secrecy::Secret;
+    use uuid::Uuid;
+
+    fn signer(secret: &str) -> TokenSigner {
+        TokenSigner::new(&Secret::new(secret.to_string()))
+    }
+
+    #[test]
+    fn issued_token_identifies_subscriber() {
+        let id = Uuid::new_v4();
+        let token = signer("secret").issue(TokenPurpose::Unsubscribe, id, TimeDelta::days(1));
+        assert_eq!(
+            signer("secret").verify(&token, TokenPurpose::Unsubscribe),
+            Ok(id)
+        );
+    }
+
+    #[test]
+    fn token_signed_with_other_secret_is_rejected() {
+        let token = signer("other").issue(
+            TokenPurpose::Unsubscribe,
+            Uuid::new_v4(),
+            TimeDelta::days(1),
+        );
+        assert_err_eq!(
+            signer("secret").verify(&token, TokenPurpose::Unsubscribe),
+            TokenError::InvalidSignature
+        );
+    }
+
+    #[test]
+    fn token_for_other_subscriber_is_rejected() {
+        let token = signer("secret").issue(
+            TokenPurpose::Unsubscribe,
+            Uuid::new_v4(),
+            TimeDelta::days(1),
+        );
+        let (_, rest) = token.split_once('.').unwrap();
+        let forged = format!("{}.{}", Uuid::new_v4(), rest);
+        assert_err_eq!(
+            signer("secret").verify(&forged, TokenPurpose::Unsubscribe),
+            TokenError::InvalidSignature
+        );
+    }
+
+    #[test]
+    fn extended_token_is_rejected() {
+        let id = Uuid::new_v4();
+        let token = signer("secret").issue(TokenPurpose::Unsubscribe, id, TimeDelta::days(1));
+        let signature = token.rsplit('.').next().unwrap();
+        let extended = format!(
+            "{}.{}.{}",
+            id,
+            (Utc::now() + TimeDelta::days(365)).timestamp(),
+            signature
+        );
+        assert_err_eq!(
+            signer("secret").verify(&extended, TokenPurpose::Unsubscribe),
+            TokenError::InvalidSignature
+        );
+    }
+
+    #[test]
+    fn expired_token_is_rejected() {
+        let signer = signer("secret");
+        let token = signer.issue_at(
+            TokenPurpose::Unsubscribe,
+            Uuid::new_v4(),
+            Utc::now() - TimeDelta::seconds(1),
+        );
+        assert_err_eq!(
+            signer.verify(&token, TokenPurpose::Unsubscribe),
+            TokenError::Expired
+        );
+    }
+
+    #[test]
+    fn token_signed_for_other_context_is_rejected() {
+        let secret = Secret::new("secret".to_string());
+        let token = HmacSigner::new(&secret, "one").sign(&[], &["a", "b"]);
+        assert_eq!(
+            HmacSigner::new(&secret, "one").verify(&token, &[]),
+            Ok(vec!["a", "b"])
+        );
+        assert_err_eq!(
+            HmacSigner::new(&secret, "other").verify(&token, &[]),
+            TokenError::InvalidSignature
+        );
+    }
+
+    #[quickcheck_macros::quickcheck]
+    fn garbage_is_rejected(token: String) -> bool {
+        signer("secret")
+            .verify(&token, TokenPurpose::Unsubscribe)
+            .is_err()
+    }
+}
@@ -0,0 +1,193 @@
+use actix_web::HttpRequest;
+use sqlx::PgPool;
+use std::time::Duration;
+
+/// At most `max_attempts` sign-ups in any `window`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Limit {
+    max_attempts: u32,
+    window: Duration,
+}
+
+/// Limits on sign-ups, so the subscription form can't be used to flood
+/// someone's inbox with confirmation emails or exhaust our sending quota.
+#[derive(Debug, Clone)]
+pub struct SignupRateLimits {
+    per_ip: Limit,
+    per_email: Limit,
+}
+
+impl Default for SignupRateLimits {
+    fn default() -> Self {
+        Self {
+            per_ip: Limit {
+                max_attempts: 10,
+                window: Duration::from_secs(60 * 60),
+            },
+            per_email: Limit {
+                max_attempts: 3,
+                window: Duration::from_secs(24 * 60 * 60),
+            },
+        }
+    }
+}
+
+impl SignupRateLimits {
+    /// Override the number of sign-ups allowed from one IP address per hour.
+    pub fn per_ip_per_hour(mut self, limit: Option<u32>) -> Self {
+        if let Some(max_attempts) = limit {
+            self.per_ip.max_attempts = max_attempts;
+        }
+        self
+    }
+
+    /// Override the number of sign-ups allowed for one email address per day.
+    pub fn per_email_per_day(mut self, limit: Option<u32>) -> Self {
+        if let Some(max_attempts) = limit {
+            self.per_email.max_attempts = max_attempts;
+        }
+        self
+    }
+}
+
+/// Which limit a sign-up exceeded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitExceeded {
+    Ip,
+    Email,
+}
+
+/// Sliding window rate limiter whose log lives in the `signup_attempts` table,
+/// so that it is shared between instances and survives restarts.
+pub struct SignupRateLimiter {
+    limits: SignupRateLimits,
+}
+
+impl SignupRateLimiter {
+    pub fn new(limits: SignupRateLimits) -> Self {
+        Self { limits }
+    }
+
+    /// Record a sign-up from `client_ip` for `email`, unless either has
+    /// already reached its limit. Rejected sign-ups aren't recorded.
+    #[tracing::instrument(skip_all)]
+    pub async fn try_record(
+        &self,
+        pool: &PgPool,
+        client_ip: Option<&str>,
+        email: &str,
+    ) -> Result<Result<(), LimitExceeded>, sqlx::Error> {
+        let mut transaction = pool.begin().await?;
+
+        let email_key = sqlx::query_scalar!(
+            r#"SELECT 'email:' || email_address_hash($1) AS "key!""#,
+            email
+        )
+        .fetch_one(&mut *transaction)
+        .await?;
+        let mut checks = vec![(email_key, self.limits.per_email, LimitExceeded::Email)];
+        if let Some(ip) = client_ip {
+            checks.push((format!("ip:{}", ip), self.limits.per_ip, LimitExceeded::Ip));
+        }
+
+        for (key, limit, exceeded) in &checks {
+            // Serialise concurrent sign-ups for the same key, so they can't all
+            // see room for one more
+            sqlx::query!(r#"SELECT pg_advisory_xact_lock(hashtext($1))"#, key)
+                .execute(&mut *transaction)
+                .await?;
+            let n_attempts = sqlx::query_scalar!(
+                r#"
+                SELECT COUNT(*) AS "count!" FROM signup_attempts
+                WHERE key = $1 AND attempted_at > NOW() - $2 * INTERVAL '1 second'
+                "#,
+                key,
+                limit.window.as_secs_f64()
+            )
+            .fetch_one(&mut *transaction)
+            .await?;
+            if n_attempts >= i64::from(limit.max_attempts) {
+                return Ok(Err(*exceeded));
+            }
+        }
+
+        for (key, _, _) in &checks {
+            sqlx::query!(r#"INSERT INTO signup_attempts (key) VALUES ($1)"#, key)
+                .execute(&mut *transaction)
+                .await?;
+        }
+
+        // Entries older than the longest window no longer count towards anything
+        let max_window = self.limits.per_ip.window.max(self.limits.per_email.window);
+        sqlx::query!(
+            r#"
+            DELETE FROM signup_attempts
+            WHERE attempted_at < NOW() - $1 * INTERVAL '1 second'
+            "#,
+            max_window.as_secs_f64()
+        )
+        .execute(&mut *transaction)
+        .await?;
+
+        transaction.commit().await?;
+        Ok(Ok(()))
+    }
+}
+
+/// The address of the client making a request. Behind nginx the peer is the
+/// proxy, which appends the client's address to `X-Forwarded-For`; anything
+/// before that was supplied by the client or Cloudflare and isn't used. nginx
+/// resolves the client's address from `CF-Connecting-IP` (see
+/// `nginx/cloudflare.conf`), so this isn't a Cloudflare edge server.
+pub fn client_ip(request: &HttpRequest) -> Option<String> {
+    let forwarded = request
+        .headers()
+        .get("X-Forwarded-For")
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.rsplit(',').next())
+        .map(str::trim)
+        .filter(|ip| !ip.is_empty())
+        .map(String::from);
+    forwarded.or_else(|| request.peer_addr().map(|addr| addr.ip().to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{client_ip, Limit, SignupRateLimits};
+    use actix_web::test::TestRequest;
+    use std::time::Duration;
+
+    #[test]
+    fn address_appended_by_nginx_is_used() {
+        // The client claimed 6.6.6.6, Cloudflare appended the address it saw,
+        // then nginx appended the address it resolved from CF-Connecting-IP
+        let request = TestRequest::default()
+            .insert_header(("X-Forwarded-For", "6.6.6.6, 1.2.3.4, 1.2.3.4"))
+            .peer_addr("10.0.0.2:80".parse().unwrap())
+            .to_http_request();
+        assert_eq!(client_ip(&request).as_deref(), Some("1.2.3.4"));
+    }
+
+    #[test]
+    fn peer_address_is_used_without_proxy() {
+        let request = TestRequest::default()
+            .peer_addr("1.2.3.4:5678".parse().unwrap())
+            .to_http_request();
+        assert_eq!(client_ip(&request).as_deref(), Some("1.2.3.4"));
+    }
+
+    #[test]
+    fn unset_overrides_keep_defaults() {
+        let limits = SignupRateLimits::default()
+            .per_ip_per_hour(None)
+            .per_email_per_day(Some(1));
+        assert_eq!(limits.per_ip, SignupRateLimits::default().per_ip);
+        assert_eq!(
+            limits.per_email,
+            Limit {
+                max_attempts: 1,
+                window: Duration::from_secs(24 * 60 * 60)
+            }
+        );
+    }
+}
@@ -1,3 +1,6 @@
+use crate::csrf::Csrf;
+use actix_session::{Session, SessionInsertError};
+use actix_web::{http::StatusCode, HttpResponse};
 use anyhow;
 use serde::Serialize;
 use tera::{Context, Tera};
@@ -29,4 +32,28 @@ impl SsrCommon {
         self.base_context.insert(key, val);
         self
     }
+
+    /// A page with `status` showing `message` under `title`, e.g. to explain
+    /// why a link couldn't be used.
+    pub fn render_message(
+        &self,
+        status: StatusCode,
+        title: &str,
+        message: &str,
+    ) -> Result<HttpResponse, tera::Error> {
+        let mut context = self.base_context.clone();
+        context.insert("title", title);
+        context.insert("message", message);
+        let html = self.tera.render("message.html", &context)?;
+        Ok(HttpResponse::build(status)
+            .content_type("text/html")
+            .body(html))
+    }
+
+    /// Make the session's CSRF token available to templates as `csrf_token`,
+    /// for forms to submit in a hidden field.
+    pub fn with_csrf_token(self, session: &Session) -> Result<Self, SessionInsertError> {
+        let token = session.csrf_token()?;
+        Ok(self.with_context("csrf_token", &token))
+    }
 }
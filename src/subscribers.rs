@@ -0,0 +1,130 @@
+use crate::{
+    email_delivery_queue,
+    subscription_events::{record_event, NewSubscriptionEvent},
+    suppressions::{suppress_address, SuppressionReason},
+};
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+/// Where a subscriber is in the subscription lifecycle. Only `Active`
+/// subscribers are sent posts; `Pending` ones are only sent their
+/// confirmation email.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[sqlx(type_name = "subscription_status", rename_all = "lowercase")]
+pub enum SubscriptionStatus {
+    /// Signed up but hasn't confirmed yet
+    Pending,
+    Active,
+    Unsubscribed,
+    /// Their address bounced, so we stopped sending to it
+    Bounced,
+}
+
+impl SubscriptionStatus {
+    /// Whether the subscriber has left, so nothing more should be sent to them.
+    pub fn has_left(self) -> bool {
+        matches!(self, Self::Unsubscribed | Self::Bounced)
+    }
+}
+
+/// Mark a subscriber as having left with `status`, cancelling anything still
+/// queued for them and expiring any links which could bring them back without
+/// signing up again. Returns their address, or `None` if there is no such
+/// subscriber or they had already left.
+#[tracing::instrument(skip_all)]
+pub async fn end_subscription(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    status: SubscriptionStatus,
+) -> Result<Option<String>, sqlx::Error> {
+    debug_assert!(status.has_left());
+    let email = sqlx::query_scalar!(
+        r#"
+        UPDATE subscriptions
+        SET status = $2, unsubscribed_at = NOW()
+        WHERE id = $1 AND status IN ('pending', 'active')
+        RETURNING email
+        "#,
+        subscriber_id,
+        status as SubscriptionStatus
+    )
+    .fetch_optional(&mut **transaction)
+    .await?;
+    if email.is_none() {
+        return Ok(None);
+    }
+
+    email_delivery_queue::cancel_subscriber_tasks(
+        &mut **transaction,
+        subscriber_id,
+        "Subscription ended",
+    )
+    .await?;
+    sqlx::query!(
+        r#"
+        UPDATE subscription_tokens
+        SET expires_at = LEAST(expires_at, NOW())
+        WHERE subscriber_id = $1 AND used_at IS NULL
+        "#,
+        subscriber_id
+    )
+    .execute(&mut **transaction)
+    .await?;
+    sqlx::query!(
+        r#"
+        UPDATE email_change_requests
+        SET expires_at = LEAST(expires_at, NOW())
+        WHERE subscriber_id = $1 AND used_at IS NULL
+        "#,
+        subscriber_id
+    )
+    .execute(&mut **transaction)
+    .await?;
+
+    Ok(email)
+}
+
+/// Unsubscribe a subscriber, adding their address to the suppression list so
+/// that they aren't re-added without confirming again, and recording `event`.
+/// Unsubscribing twice is harmless.
+#[tracing::instrument(skip_all)]
+pub async fn remove_subscriber(
+    connection_pool: &PgPool,
+    subscriber_id: Uuid,
+    event: &NewSubscriptionEvent,
+) -> Result<(), sqlx::Error> {
+    let mut transaction = connection_pool.begin().await?;
+
+    let email = end_subscription(
+        &mut transaction,
+        subscriber_id,
+        SubscriptionStatus::Unsubscribed,
+    )
+    .await?;
+
+    if let Some(email) = email {
+        suppress_address(
+            &mut *transaction,
+            &email,
+            SuppressionReason::Unsubscribed,
+            None,
+        )
+        .await?;
+        record_event(&mut *transaction, subscriber_id, event).await?;
+    }
+
+    transaction.commit().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SubscriptionStatus;
+
+    #[test]
+    fn only_unsubscribed_and_bounced_subscribers_have_left() {
+        assert!(!SubscriptionStatus::Pending.has_left());
+        assert!(!SubscriptionStatus::Active.has_left());
+        assert!(SubscriptionStatus::Unsubscribed.has_left());
+        assert!(SubscriptionStatus::Bounced.has_left());
+    }
+}
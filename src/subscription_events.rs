@@ -0,0 +1,300 @@
+use crate::{digest::DeliveryMode, signup_rate_limit::client_ip};
+use actix_web::{http::header::USER_AGENT, HttpRequest};
+use chrono::{DateTime, Utc};
+use sqlx::{Executor, Postgres};
+use uuid::Uuid;
+
+/// Longest user agent or source we keep. Both come from the client, so are
+/// cut short rather than stored whole.
+const MAX_CLIENT_VALUE_LENGTH: usize = 256;
+
+/// Something that happened to a subscription, recorded so we can show when
+/// and how someone consented to hear from us.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[sqlx(type_name = "subscription_event_kind", rename_all = "snake_case")]
+pub enum SubscriptionEventKind {
+    SignedUp,
+    Confirmed,
+    ConfirmationResent,
+    PreferencesChanged,
+    EmailChanged,
+    Unsubscribed,
+    Bounced,
+}
+
+impl std::fmt::Display for SubscriptionEventKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::SignedUp => write!(f, "signed up"),
+            Self::Confirmed => write!(f, "confirmed"),
+            Self::ConfirmationResent => write!(f, "confirmation resent"),
+            Self::PreferencesChanged => write!(f, "preferences changed"),
+            Self::EmailChanged => write!(f, "email changed"),
+            Self::Unsubscribed => write!(f, "unsubscribed"),
+            Self::Bounced => write!(f, "bounced"),
+        }
+    }
+}
+
+/// A recorded event, as returned by [`events_for_subscriber`].
+#[derive(Debug, Clone)]
+pub struct SubscriptionEvent {
+    pub kind: SubscriptionEventKind,
+    pub occurred_at: DateTime<Utc>,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    pub source: Option<String>,
+    pub detail: Option<String>,
+}
+
+/// An event waiting to be recorded with [`record_event`].
+#[derive(Debug, Clone)]
+pub struct NewSubscriptionEvent {
+    kind: SubscriptionEventKind,
+    ip_address: Option<String>,
+    user_agent: Option<String>,
+    source: Option<String>,
+    detail: Option<String>,
+}
+
+impl NewSubscriptionEvent {
+    pub fn new(kind: SubscriptionEventKind) -> Self {
+        Self {
+            kind,
+            ip_address: None,
+            user_agent: None,
+            source: None,
+            detail: None,
+        }
+    }
+
+    /// Record where the request which caused the event came from.
+    pub fn request(mut self, request: &HttpRequest) -> Self {
+        self.ip_address = client_ip(request);
+        self.user_agent = request
+            .headers()
+            .get(USER_AGENT)
+            .and_then(|h| h.to_str().ok())
+            .map(truncate);
+        self
+    }
+
+    /// What caused the event, e.g. the form a sign-up came from. Empty
+    /// sources are ignored.
+    pub fn source(mut self, source: &str) -> Self {
+        self.source = Some(source.trim()).filter(|s| !s.is_empty()).map(truncate);
+        self
+    }
+
+    pub fn detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+}
+
+/// Describe what a subscriber chose to receive, as the detail of a sign-up or
+/// preference change.
+pub fn describe_preferences(
+    delivery_mode: DeliveryMode,
+    topics: &[String],
+    paused_until: Option<DateTime<Utc>>,
+) -> String {
+    let delivery_mode = match delivery_mode {
+        DeliveryMode::Immediate => "immediate",
+        DeliveryMode::WeeklyDigest => "weekly digest",
+    };
+    let topics = if topics.is_empty() {
+        "all".to_string()
+    } else {
+        topics.join(", ")
+    };
+    let mut detail = format!("Delivery: {}; topics: {}", delivery_mode, topics);
+    if let Some(paused_until) = paused_until {
+        detail.push_str(&format!("; paused until {}", paused_until.to_rfc3339()));
+    }
+    detail
+}
+
+fn truncate(value: &str) -> String {
+    value.chars().take(MAX_CLIENT_VALUE_LENGTH).collect()
+}
+
+/// Record an event for a subscriber. Call this within the transaction which
+/// makes the change, so the record and the change stand or fall together.
+#[tracing::instrument(skip_all, fields(kind = ?event.kind))]
+pub async fn record_event<'a, T>(
+    executor: T,
+    subscriber_id: Uuid,
+    event: &NewSubscriptionEvent,
+) -> Result<(), sqlx::Error>
+where
+    T: Executor<'a, Database = Postgres>,
+{
+    sqlx::query!(
+        r#"
+        INSERT INTO subscription_events
+            (subscriber_id, kind, ip_address, user_agent, source, detail)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        "#,
+        subscriber_id,
+        event.kind as SubscriptionEventKind,
+        event.ip_address,
+        event.user_agent,
+        event.source,
+        event.detail
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+/// Everything that has happened to a subscriber's subscription, oldest first.
+#[tracing::instrument(skip_all)]
+pub async fn events_for_subscriber<'a, T>(
+    executor: T,
+    subscriber_id: Uuid,
+) -> Result<Vec<SubscriptionEvent>, sqlx::Error>
+where
+    T: Executor<'a, Database = Postgres>,
+{
+    sqlx::query_as!(
+        SubscriptionEvent,
+        r#"
+        SELECT
+            kind AS "kind: SubscriptionEventKind",
+            occurred_at,
+            ip_address,
+            user_agent,
+            source,
+            detail
+        FROM subscription_events
+        WHERE subscriber_id = $1
+        ORDER BY occurred_at, id
+        "#,
+        subscriber_id
+    )
+    .fetch_all(executor)
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        describe_preferences, events_for_subscriber, record_event, NewSubscriptionEvent,
+        SubscriptionEventKind, MAX_CLIENT_VALUE_LENGTH,
+    };
+    use crate::digest::DeliveryMode;
+    use actix_web::test::TestRequest;
+    use claims::{assert_none, assert_some_eq};
+    use sqlx::PgPool;
+    use uuid::Uuid;
+
+    #[test]
+    fn request_details_are_recorded() {
+        let request = TestRequest::default()
+            .insert_header(("User-Agent", "Mozilla/5.0"))
+            .insert_header(("X-Forwarded-For", "203.0.113.7"))
+            .to_http_request();
+        let event = NewSubscriptionEvent::new(SubscriptionEventKind::SignedUp).request(&request);
+        assert_some_eq!(event.ip_address, "203.0.113.7");
+        assert_some_eq!(event.user_agent, "Mozilla/5.0");
+    }
+
+    #[test]
+    fn client_supplied_values_are_truncated() {
+        let request = TestRequest::default()
+            .insert_header(("User-Agent", "a".repeat(1000)))
+            .to_http_request();
+        let event = NewSubscriptionEvent::new(SubscriptionEventKind::SignedUp)
+            .request(&request)
+            .source(&"b".repeat(1000));
+        assert_eq!(event.user_agent.unwrap().len(), MAX_CLIENT_VALUE_LENGTH);
+        assert_eq!(event.source.unwrap().len(), MAX_CLIENT_VALUE_LENGTH);
+    }
+
+    #[test]
+    fn empty_source_is_ignored() {
+        let event = NewSubscriptionEvent::new(SubscriptionEventKind::SignedUp).source("  ");
+        assert_none!(event.source);
+    }
+
+    #[test]
+    fn preferences_are_described() {
+        assert_eq!(
+            describe_preferences(DeliveryMode::Immediate, &[], None),
+            "Delivery: immediate; topics: all"
+        );
+        assert_eq!(
+            describe_preferences(
+                DeliveryMode::WeeklyDigest,
+                &["career".to_string(), "rust".to_string()],
+                None
+            ),
+            "Delivery: weekly digest; topics: career, rust"
+        );
+    }
+
+    #[test]
+    fn kinds_are_described_in_words() {
+        assert_eq!(SubscriptionEventKind::SignedUp.to_string(), "signed up");
+        assert_eq!(
+            SubscriptionEventKind::ConfirmationResent.to_string(),
+            "confirmation resent"
+        );
+    }
+
+    /// Needs a database, so run with `cargo test -- --ignored` and
+    /// `DATABASE_URL` set.
+    #[ignore]
+    #[sqlx::test]
+    async fn events_are_listed_oldest_first(pool: PgPool) {
+        let subscriber_id = Uuid::new_v4();
+        sqlx::query("INSERT INTO subscriptions (id, email, subscribed_at) VALUES ($1, $2, NOW())")
+            .bind(subscriber_id)
+            .bind("reader@tld.com")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        // Recorded in one transaction, so these share a timestamp and are
+        // ordered by when they were recorded
+        let mut transaction = pool.begin().await.unwrap();
+        for kind in [
+            SubscriptionEventKind::SignedUp,
+            SubscriptionEventKind::Confirmed,
+        ] {
+            record_event(
+                &mut *transaction,
+                subscriber_id,
+                &NewSubscriptionEvent::new(kind),
+            )
+            .await
+            .unwrap();
+        }
+        transaction.commit().await.unwrap();
+        // Recorded last, but happened first
+        sqlx::query(
+            "INSERT INTO subscription_events (subscriber_id, kind, occurred_at) \
+            VALUES ($1, 'bounced', NOW() - INTERVAL '1 day')",
+        )
+        .bind(subscriber_id)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let kinds: Vec<SubscriptionEventKind> = events_for_subscriber(&pool, subscriber_id)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|event| event.kind)
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                SubscriptionEventKind::Bounced,
+                SubscriptionEventKind::SignedUp,
+                SubscriptionEventKind::Confirmed,
+            ]
+        );
+    }
+}
@@ -0,0 +1,209 @@
+use chrono::{DateTime, TimeDelta, Utc};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use sqlx::{Executor, PgPool, Postgres, Transaction};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing_log::log;
+use uuid::Uuid;
+
+/// How long a confirmation link works for after it is sent.
+pub const CONFIRMATION_TOKEN_LIFETIME: TimeDelta = TimeDelta::hours(48);
+
+/// How long expired and used tokens are kept, so that following an old link
+/// explains what happened and offers to resend it rather than just failing.
+const TOKEN_RETENTION: TimeDelta = TimeDelta::days(30);
+
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// A confirmation token as stored in `subscription_tokens`.
+#[derive(Debug, Clone)]
+pub struct SubscriptionToken {
+    pub subscriber_id: Uuid,
+    pub expires_at: DateTime<Utc>,
+    pub used_at: Option<DateTime<Utc>>,
+}
+
+/// Whether a token can still be used to confirm a subscription.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenStatus {
+    Valid,
+    Used,
+    Expired,
+}
+
+impl SubscriptionToken {
+    pub fn status(&self, now: DateTime<Utc>) -> TokenStatus {
+        if self.used_at.is_some() {
+            TokenStatus::Used
+        } else if self.expires_at <= now {
+            TokenStatus::Expired
+        } else {
+            TokenStatus::Valid
+        }
+    }
+}
+
+pub(crate) fn generate_subscription_token() -> String {
+    let mut rng = thread_rng();
+    std::iter::repeat_with(|| rng.sample(Alphanumeric))
+        .map(char::from)
+        .take(25)
+        .collect()
+}
+
+/// Issue a new confirmation token for a subscriber, expiring any they haven't
+/// used yet so that only the most recent link works.
+#[tracing::instrument(skip_all)]
+pub async fn rotate_token(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+) -> Result<String, sqlx::Error> {
+    sqlx::query!(
+        r#"
+        UPDATE subscription_tokens
+        SET expires_at = LEAST(expires_at, NOW())
+        WHERE subscriber_id = $1 AND used_at IS NULL
+        "#,
+        subscriber_id
+    )
+    .execute(&mut **transaction)
+    .await?;
+
+    let token = generate_subscription_token();
+    sqlx::query!(
+        r#"
+        INSERT INTO subscription_tokens (subscription_token, subscriber_id, expires_at)
+        VALUES ($1, $2, $3)
+        "#,
+        token,
+        subscriber_id,
+        Utc::now() + CONFIRMATION_TOKEN_LIFETIME
+    )
+    .execute(&mut **transaction)
+    .await?;
+    Ok(token)
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn get_token<'a, T>(
+    executor: T,
+    token: &str,
+) -> Result<Option<SubscriptionToken>, sqlx::Error>
+where
+    T: Executor<'a, Database = Postgres>,
+{
+    sqlx::query_as!(
+        SubscriptionToken,
+        r#"
+        SELECT subscriber_id, expires_at, used_at FROM subscription_tokens
+        WHERE subscription_token = $1
+        "#,
+        token
+    )
+    .fetch_optional(executor)
+    .await
+}
+
+/// Mark a token as used, returning the subscriber it belongs to. Returns
+/// `None` if the token is unknown, expired or has already been used, so a
+/// token can only be used once even by concurrent requests.
+#[tracing::instrument(skip_all)]
+pub async fn use_token(
+    transaction: &mut Transaction<'_, Postgres>,
+    token: &str,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    sqlx::query_scalar!(
+        r#"
+        UPDATE subscription_tokens
+        SET used_at = NOW()
+        WHERE subscription_token = $1 AND used_at IS NULL AND expires_at > NOW()
+        RETURNING subscriber_id
+        "#,
+        token
+    )
+    .fetch_optional(&mut **transaction)
+    .await
+}
+
+/// Delete tokens which expired or were used more than `TOKEN_RETENTION` ago,
+/// returning the number deleted.
+#[tracing::instrument(skip_all)]
+pub async fn delete_stale_tokens<'a, T>(executor: T) -> Result<u64, sqlx::Error>
+where
+    T: Executor<'a, Database = Postgres>,
+{
+    let cutoff = Utc::now() - TOKEN_RETENTION;
+    let result = sqlx::query!(
+        r#"
+        DELETE FROM subscription_tokens
+        WHERE expires_at < $1 OR used_at < $1
+        "#,
+        cutoff
+    )
+    .execute(executor)
+    .await?;
+    Ok(result.rows_affected())
+}
+
+/// Background task which periodically deletes stale tokens.
+pub async fn token_cleanup(connection_pool: Arc<PgPool>) {
+    loop {
+        match delete_stale_tokens(&*connection_pool).await {
+            Ok(0) => log::debug!("No stale subscription tokens"),
+            Ok(n) => log::info!("Deleted {} stale subscription tokens", n),
+            Err(e) => log::error!("Failed to delete stale subscription tokens: {}", e),
+        }
+        tokio::time::sleep(CLEANUP_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{generate_subscription_token, SubscriptionToken, TokenStatus};
+    use chrono::{TimeDelta, Utc};
+    use uuid::Uuid;
+
+    fn token(expires_in: TimeDelta, used: bool) -> SubscriptionToken {
+        let now = Utc::now();
+        SubscriptionToken {
+            subscriber_id: Uuid::new_v4(),
+            expires_at: now + expires_in,
+            used_at: used.then_some(now),
+        }
+    }
+
+    #[test]
+    fn unused_token_is_valid_until_it_expires() {
+        let now = Utc::now();
+        assert_eq!(
+            token(TimeDelta::hours(1), false).status(now),
+            TokenStatus::Valid
+        );
+        assert_eq!(
+            token(-TimeDelta::hours(1), false).status(now),
+            TokenStatus::Expired
+        );
+    }
+
+    #[test]
+    fn used_token_is_used_even_after_expiry() {
+        let now = Utc::now();
+        assert_eq!(
+            token(TimeDelta::hours(1), true).status(now),
+            TokenStatus::Used
+        );
+        assert_eq!(
+            token(-TimeDelta::hours(1), true).status(now),
+            TokenStatus::Used
+        );
+    }
+
+    #[test]
+    fn generated_tokens_are_alphanumeric() {
+        let token = generate_subscription_token();
+        assert_eq!(token.len(), 25);
+        assert!(token.chars().all(|c| c.is_ascii_alphanumeric()));
+        assert_ne!(token, generate_subscription_token());
+    }
+}
@@ -0,0 +1,131 @@
+use crate::bounces::BounceKind;
+use sqlx::{Executor, Postgres};
+
+/// Why an address is on the suppression list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[sqlx(type_name = "email_suppression_reason", rename_all = "lowercase")]
+pub enum SuppressionReason {
+    /// The subscriber unsubscribed. They may subscribe again, but won't be
+    /// re-added without confirming.
+    Unsubscribed,
+    Bounced,
+    /// The recipient marked our email as spam
+    Complaint,
+    /// Added by hand, e.g. on request
+    Manual,
+}
+
+impl SuppressionReason {
+    /// Whether a fresh double opt-in lifts the suppression.
+    pub fn allows_resubscribe(self) -> bool {
+        self == Self::Unsubscribed
+    }
+}
+
+impl From<BounceKind> for SuppressionReason {
+    fn from(kind: BounceKind) -> Self {
+        match kind {
+            BounceKind::HardBounce | BounceKind::SoftBounce => Self::Bounced,
+            BounceKind::Complaint => Self::Complaint,
+        }
+    }
+}
+
+/// Add an address to the suppression list. An existing `Unsubscribed` entry is
+/// replaced, but other reasons are kept, so e.g. a complaint isn't downgraded
+/// by a later unsubscribe.
+#[tracing::instrument(skip_all)]
+pub async fn suppress_address<'a, T>(
+    executor: T,
+    email: &str,
+    reason: SuppressionReason,
+    detail: Option<&str>,
+) -> Result<(), sqlx::Error>
+where
+    T: Executor<'a, Database = Postgres>,
+{
+    sqlx::query!(
+        r#"
+        INSERT INTO email_suppressions (address_hash, reason, detail)
+        VALUES (email_address_hash($1), $2, $3)
+        ON CONFLICT (address_hash) DO UPDATE
+        SET reason = EXCLUDED.reason, detail = EXCLUDED.detail, suppressed_at = NOW()
+        WHERE email_suppressions.reason = 'unsubscribed'
+        "#,
+        email,
+        reason as SuppressionReason,
+        detail
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+/// The reason an address is suppressed, if it is.
+#[tracing::instrument(skip_all)]
+pub async fn suppression_reason<'a, T>(
+    executor: T,
+    email: &str,
+) -> Result<Option<SuppressionReason>, sqlx::Error>
+where
+    T: Executor<'a, Database = Postgres>,
+{
+    sqlx::query_scalar!(
+        r#"
+        SELECT reason AS "reason: SuppressionReason" FROM email_suppressions
+        WHERE address_hash = email_address_hash($1)
+        "#,
+        email
+    )
+    .fetch_optional(executor)
+    .await
+}
+
+/// Lift an `Unsubscribed` suppression once the address has confirmed a new
+/// subscription. Other suppressions are left in place.
+#[tracing::instrument(skip_all)]
+pub async fn lift_unsubscribed<'a, T>(executor: T, email: &str) -> Result<(), sqlx::Error>
+where
+    T: Executor<'a, Database = Postgres>,
+{
+    sqlx::query!(
+        r#"
+        DELETE FROM email_suppressions
+        WHERE address_hash = email_address_hash($1) AND reason = 'unsubscribed'
+        "#,
+        email
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SuppressionReason;
+    use crate::bounces::BounceKind;
+
+    #[test]
+    fn bounces_and_complaints_are_distinguished() {
+        assert_eq!(
+            SuppressionReason::from(BounceKind::HardBounce),
+            SuppressionReason::Bounced
+        );
+        assert_eq!(
+            SuppressionReason::from(BounceKind::SoftBounce),
+            SuppressionReason::Bounced
+        );
+        assert_eq!(
+            SuppressionReason::from(BounceKind::Complaint),
+            SuppressionReason::Complaint
+        );
+    }
+
+    #[test]
+    fn only_unsubscribed_addresses_may_resubscribe() {
+        assert!(SuppressionReason::Unsubscribed.allows_resubscribe());
+        assert!(!SuppressionReason::Bounced.allows_resubscribe());
+        assert!(!SuppressionReason::Complaint.allows_resubscribe());
+        assert!(!SuppressionReason::Manual.allows_resubscribe());
+    }
+}
@@ -0,0 +1,25 @@
+//! Helpers shared by unit tests.
+
+use fake::faker::internet::en::SafeEmail;
+use fake::Fake;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::sync::Once;
+
+/// A random valid email address, for quickcheck tests.
+#[derive(Debug, Clone)]
+pub struct ValidEmailFixture(pub String);
+
+impl quickcheck::Arbitrary for ValidEmailFixture {
+    fn arbitrary<G: quickcheck::Gen>(g: &mut G) -> Self {
+        let mut rng = StdRng::seed_from_u64(u64::arbitrary(g));
+        Self(SafeEmail().fake_with_rng(&mut rng))
+    }
+}
+
+/// Set `APP_BASE_URL` for code building links with `app_url`. It is only set
+/// once, so tests running in parallel never see it change.
+pub fn set_app_base_url() {
+    static SET: Once = Once::new();
+    SET.call_once(|| std::env::set_var("APP_BASE_URL", "https://tld.com"));
+}
@@ -0,0 +1,66 @@
+use serde::Serialize;
+use sqlx::{Executor, Postgres, Transaction};
+use uuid::Uuid;
+
+/// Something posts can be about, which subscribers can choose to hear about.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Topic {
+    pub slug: String,
+    pub name: String,
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn all_topics<'a, T>(executor: T) -> Result<Vec<Topic>, sqlx::Error>
+where
+    T: Executor<'a, Database = Postgres>,
+{
+    sqlx::query_as!(Topic, r#"SELECT slug, name FROM topics ORDER BY name"#)
+        .fetch_all(executor)
+        .await
+}
+
+/// The topics a subscriber has chosen. Empty if they want everything.
+#[tracing::instrument(skip_all)]
+pub async fn subscriber_topics<'a, T>(
+    executor: T,
+    subscriber_id: Uuid,
+) -> Result<Vec<String>, sqlx::Error>
+where
+    T: Executor<'a, Database = Postgres>,
+{
+    sqlx::query_scalar!(
+        r#"SELECT topic FROM subscription_topics WHERE subscriber_id = $1 ORDER BY topic"#,
+        subscriber_id
+    )
+    .fetch_all(executor)
+    .await
+}
+
+/// Replace a subscriber's topics, returning those stored. Unknown topics are
+/// ignored, and choosing none means everything.
+#[tracing::instrument(skip_all)]
+pub async fn set_subscriber_topics(
+    transaction: &mut Transaction<'_, Postgres>,
+    subscriber_id: Uuid,
+    topics: &[String],
+) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query!(
+        r#"DELETE FROM subscription_topics WHERE subscriber_id = $1"#,
+        subscriber_id
+    )
+    .execute(&mut **transaction)
+    .await?;
+    let mut stored = sqlx::query_scalar!(
+        r#"
+        INSERT INTO subscription_topics (subscriber_id, topic)
+        SELECT $1, slug FROM topics WHERE slug = ANY($2)
+        RETURNING topic
+        "#,
+        subscriber_id,
+        topics
+    )
+    .fetch_all(&mut **transaction)
+    .await?;
+    stored.sort();
+    Ok(stored)
+}
@@ -1,3 +1,5 @@
+use serde::de::{value::Error as ValueError, value::MapDeserializer, DeserializeOwned};
+
 pub fn e500<T>(e: T) -> actix_web::Error
 where
     T: 'static + std::fmt::Debug + std::fmt::Display,
@@ -21,3 +23,89 @@ pub fn error_chain_fmt(
 pub fn read_env_or_panic(varname: &str) -> String {
     std::env::var(varname).unwrap_or_else(|_| panic!("Failed to read env var {}", varname))
 }
+
+pub fn read_optional_env<T>(varname: &str) -> Option<T>
+where
+    T: std::str::FromStr,
+{
+    std::env::var(varname).ok().map(|s| {
+        s.parse()
+            .unwrap_or_else(|_| panic!("Failed to parse env var {}", varname))
+    })
+}
+
+/// Absolute URL of `path` on the dynamic site, e.g. `app_url("/blog")`.
+pub fn app_url(path: &str) -> String {
+    let app_base_url = read_env_or_panic("APP_BASE_URL");
+    format!(
+        "{}/{}",
+        app_base_url.trim_end_matches('/'),
+        path.trim_start_matches('/')
+    )
+}
+
+/// Compare secrets without leaking how much of them matched through timing.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Deserialize url-encoded form fields into `T`, apart from those named
+/// `repeated`, whose values are returned separately. `web::Form` can't collect
+/// a field which appears more than once, such as a group of checkboxes.
+pub fn form_with_repeated<T>(
+    fields: Vec<(String, String)>,
+    repeated: &str,
+) -> Result<(T, Vec<String>), ValueError>
+where
+    T: DeserializeOwned,
+{
+    let (values, rest): (Vec<_>, Vec<_>) =
+        fields.into_iter().partition(|(name, _)| name == repeated);
+    let form = T::deserialize(MapDeserializer::<_, ValueError>::new(rest.into_iter()))?;
+    Ok((form, values.into_iter().map(|(_, value)| value).collect()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::form_with_repeated;
+    use claims::assert_err;
+
+    #[derive(serde::Deserialize, Debug, PartialEq)]
+    struct Form {
+        email: String,
+        #[serde(default)]
+        website: String,
+    }
+
+    fn fields(fields: &[(&str, &str)]) -> Vec<(String, String)> {
+        fields
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn repeated_field_is_collected_separately() {
+        let (form, topics): (Form, _) = form_with_repeated(
+            fields(&[("topic", "rust"), ("email", "a@b.com"), ("topic", "career")]),
+            "topic",
+        )
+        .unwrap();
+        assert_eq!(
+            form,
+            Form {
+                email: "a@b.com".into(),
+                website: String::new()
+            }
+        );
+        assert_eq!(topics, vec!["rust", "career"]);
+    }
+
+    #[test]
+    fn missing_required_field_is_an_error() {
+        assert_err!(form_with_repeated::<Form>(
+            fields(&[("topic", "rust")]),
+            "topic"
+        ));
+    }
+}